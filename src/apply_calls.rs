@@ -0,0 +1,255 @@
+use crate::block_builder::{BlockConfig, BlockProducer};
+use crate::metadata::{load_runtime_info, RuntimeInfo, CODE_KEY};
+use crate::scale_json::{decode_all, encode as encode_value, parse_json_arg};
+use crate::state_file::{externalities_key_values, load_externalities, write_state};
+use crate::state_source::RpcConfig;
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use serde_json::{json, Value};
+use sp_runtime::traits::NumberFor;
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Maximum length of a call stored inline in the scheduler, bigger calls need a preimage.
+const MAX_INLINE_CALL_LEN: usize = 128;
+
+/// Configurations for [`apply_calls`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct ApplyCallsCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to modify. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Call to dispatch. Either a SCALE encoded `RuntimeCall` as 0x prefixed hex, or JSON as
+    /// `{"Pallet": {"call_name": {"arg": value}}}`. Can be provided multiple times, calls are
+    /// dispatched in order.
+    #[clap(long = "call", required = true)]
+    pub calls: Vec<String>,
+
+    /// Origin of the calls, as JSON of the `OriginCaller` type.
+    #[clap(long, default_value = r#"{"system": "Root"}"#)]
+    pub origin: String,
+
+    /// Write the resulting state into this raw chain spec, replacing all of its keys. If not
+    /// set, a hex snapshot is written to `--output-path`.
+    #[clap(long)]
+    pub chain_spec_path: Option<String>,
+
+    /// Output path. Defaults to the chain spec path if `--chain-spec-path` is set. A path ending
+    /// in `.snap` is written as a snapshot of the produced block, which keeps the child tries.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    #[command(flatten)]
+    pub block_config: BlockConfig,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// Encode a call given as `{"Pallet": {"call_name": args}}` into a `RuntimeCall`, which is the
+/// pallet index followed by the pallet call enum.
+fn encode_call(runtime: &RuntimeInfo, call: &Value) -> Result<Vec<u8>, String> {
+    let (pallet_name, pallet_call) = call
+        .as_object()
+        .filter(|call| call.len() == 1)
+        .and_then(|call| call.iter().next())
+        .ok_or_else(|| format!("Expected call as {{\"Pallet\": call}}, found: {}", call))?;
+    let pallet = runtime
+        .pallet(pallet_name)
+        .ok_or_else(|| format!("Pallet {} not found in metadata", pallet_name))?;
+    let calls_ty = pallet
+        .calls
+        .ok_or_else(|| format!("Pallet {} has no calls", pallet_name))?;
+
+    let mut encoded = vec![pallet.index];
+    encoded.extend(encode_value(&runtime.types, calls_ty, pallet_call)?);
+
+    Ok(encoded)
+}
+
+fn parse_call(runtime: &RuntimeInfo, call: &str) -> Result<Vec<u8>, String> {
+    match call.strip_prefix("0x") {
+        Some(call_hex) => {
+            hex::decode(call_hex).map_err(|e| format!("Invalid hex call {}: {}", call, e))
+        }
+        None => encode_call(runtime, &parse_json_arg(call)),
+    }
+}
+
+pub async fn apply_calls<Block, HostFns>(
+    shared: SharedParams,
+    command: ApplyCallsCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let output_path = match (&command.output_path, &command.chain_spec_path) {
+        (Some(output_path), _) => output_path.clone(),
+        (None, Some(chain_spec_path)) => chain_spec_path.clone(),
+        (None, None) => return Err("Pass --output-path or --chain-spec-path".into()),
+    };
+
+    let (mut ext, block_hash) =
+        load_externalities::<Block, HostFns>(&shared, &command.state_path, &command.rpc).await?;
+    let code = ext
+        .execute_with(|| sp_io::storage::get(CODE_KEY))
+        .ok_or("State does not contain :code")?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+    let origin = parse_json_arg(&command.origin);
+
+    // The calls are scheduled for the next block, so the scheduler dispatches them with the
+    // given origin during `on_initialize`
+    let number_item = runtime.storage_item("System", "Number")?;
+    let number = match ext.execute_with(|| sp_io::storage::get(&number_item.prefix())) {
+        Some(number) => decode_all(&runtime.types, number_item.value_type(), &number)?,
+        None => Value::from(0),
+    };
+    let next_number = number
+        .as_u64()
+        .ok_or_else(|| format!("Unexpected block number: {}", number))?
+        + 1;
+
+    if runtime.pallet("Scheduler").is_none() {
+        return Err(
+            "apply-calls dispatches the calls with the Scheduler pallet, which is not in \
+                    the runtime"
+                .into(),
+        );
+    }
+    let agenda_item = runtime.storage_item("Scheduler", "Agenda")?;
+    let agenda_key = agenda_item.key_from_json(&runtime.types, &[Value::from(next_number)])?;
+    let mut agenda = match ext.execute_with(|| sp_io::storage::get(&agenda_key)) {
+        Some(agenda) => decode_all(&runtime.types, agenda_item.value_type(), &agenda)?,
+        None => Value::Array(vec![]),
+    };
+    // Tasks already scheduled for that block are dispatched too, the calls are added after them
+    let first_index = agenda
+        .as_array()
+        .ok_or("Unexpected Scheduler::Agenda format")?
+        .len() as u64;
+    let max_per_block = runtime.constant::<u32>("Scheduler", "MaxScheduledPerBlock")?;
+    if first_index + command.calls.len() as u64 > u64::from(max_per_block) {
+        return Err(format!(
+            "Block {} already has {} scheduled tasks, {} more calls do not fit in \
+             Scheduler::MaxScheduledPerBlock ({})",
+            next_number,
+            first_index,
+            command.calls.len(),
+            max_per_block
+        )
+        .into());
+    }
+    for call in &command.calls {
+        let call = parse_call(&runtime, call)?;
+        if call.len() > MAX_INLINE_CALL_LEN {
+            return Err(format!(
+                "Call 0x{} is {} bytes long, only calls up to {} bytes are supported",
+                hex::encode(&call),
+                call.len(),
+                MAX_INLINE_CALL_LEN
+            )
+            .into());
+        }
+        log::info!("Scheduling call 0x{}", hex::encode(&call));
+        agenda
+            .as_array_mut()
+            .ok_or("Unexpected Scheduler::Agenda format")?
+            .push(json!({
+                "maybe_id": null,
+                "priority": 0,
+                "call": {"Inline": format!("0x{}", hex::encode(&call))},
+                "maybe_periodic": null,
+                "origin": origin,
+            }));
+    }
+    let agenda = encode_value(&runtime.types, agenda_item.value_type(), &agenda)?;
+    ext.insert(agenda_key, agenda);
+    ext.commit_all()?;
+
+    let mut producer =
+        BlockProducer::<Block, HostFns>::new(&shared, ext, block_hash, command.block_config)?;
+    let header = producer.produce_block().inspect_err(|e| {
+        log::error!("Failed to produce block: {}", e);
+    })?;
+
+    // Check the result of each call in the events of the block
+    let events_item = runtime.storage_item("System", "Events")?;
+    let events = match producer
+        .ext
+        .execute_with(|| sp_io::storage::get(&events_item.prefix()))
+    {
+        Some(events) => decode_all(&runtime.types, events_item.value_type(), &events)?,
+        None => Value::Array(vec![]),
+    };
+    // The task of each call is its block number and its index in the agenda
+    let mut results = vec![None; command.calls.len()];
+    let dispatched = events
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|record| record.pointer("/event/Scheduler/Dispatched"));
+    for event in dispatched {
+        let task = (
+            event.pointer("/task/0").and_then(Value::as_u64),
+            event.pointer("/task/1").and_then(Value::as_u64),
+        );
+        let (Some(number), Some(index)) = task else {
+            return Err(format!("Unexpected Scheduler::Dispatched event: {}", event).into());
+        };
+        let Some(result) = index
+            .checked_sub(first_index)
+            .filter(|_| number == next_number)
+            .and_then(|i| results.get_mut(i as usize))
+        else {
+            continue;
+        };
+        *result = event.get("result");
+    }
+    let mut failed = 0;
+    for (i, result) in results.iter().enumerate() {
+        match result.and_then(|result| result.get("Err")) {
+            Some(e) => {
+                failed += 1;
+                log::error!("Call {} failed: {}", i, e);
+            }
+            None if result.is_some() => log::info!("Call {} dispatched successfully", i),
+            None => {}
+        }
+    }
+    let dispatched = results.iter().filter(|result| result.is_some()).count();
+    if dispatched < command.calls.len() {
+        return Err(format!(
+            "Only {} of {} calls were dispatched, check the scheduler weight limit",
+            dispatched,
+            command.calls.len()
+        )
+        .into());
+    }
+    if failed > 0 {
+        return Err(format!("{} calls failed", failed).into());
+    }
+
+    let key_values = externalities_key_values(&mut producer.ext);
+    write_state::<Block>(
+        key_values,
+        header,
+        producer.ext.state_version,
+        command.chain_spec_path.as_deref(),
+        &output_path,
+    )?;
+    log::warn!(
+        "The state advanced one block, including its number, timestamp and other scheduled \
+         tasks, use rebase-time to reset the time"
+    );
+
+    Ok(())
+}
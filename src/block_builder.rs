@@ -0,0 +1,285 @@
+//! Produce blocks offline on top of some externalities, using the block builder runtime api.
+
+use crate::metadata::storage_prefix;
+use crate::should_be_public::{build_executor, parse, state_machine_call};
+use cumulus_primitives_parachain_inherent::{
+    ParachainInherentData, INHERENT_IDENTIFIER as PARACHAIN_INHERENT_IDENTIFIER,
+};
+use cumulus_test_relay_sproof_builder::RelayStateSproofBuilder;
+use parity_scale_codec::{Decode, DecodeAll, Encode, Output};
+use polkadot_primitives::PersistedValidationData;
+use sc_executor::{HostFunctions, WasmExecutor};
+use sp_inherents::{InherentData, InherentIdentifier};
+use sp_runtime::traits::{
+    Block as BlockT, Hash as HashT, HashingFor, Header as HeaderT, NumberFor, One, Zero,
+};
+use sp_runtime::{ApplyExtrinsicResult, Digest, DigestItem};
+use sp_state_machine::TestExternalities;
+use std::time::{SystemTime, UNIX_EPOCH};
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+const TIMESTAMP_INHERENT_IDENTIFIER: InherentIdentifier = *b"timstap0";
+const AURA_ENGINE_ID: [u8; 4] = *b"aura";
+/// Relay chain slot duration in milliseconds
+const RELAY_SLOT_DURATION: u64 = 6000;
+
+/// Which inherents and digests to include in each block.
+#[derive(Debug, Clone, clap::Args)]
+pub struct BlockConfig {
+    /// Time between blocks in milliseconds, also used as the aura slot duration.
+    #[clap(long, default_value_t = 6000)]
+    pub block_time: u64,
+
+    /// Add an aura pre-runtime digest with the slot of each block.
+    #[clap(long)]
+    pub aura: bool,
+
+    /// Add a `set_validation_data` inherent with a mocked relay chain state, needed by parachain
+    /// runtimes. The para id is read from `ParachainInfo::ParachainId`.
+    #[clap(long)]
+    pub parachain: bool,
+
+    /// Extra inherent data as `identifier=0x<scale encoded data>`, where identifier is 8 ascii
+    /// characters. Can be provided multiple times.
+    #[clap(long = "inherent", value_parser = parse_inherent)]
+    pub inherents: Vec<(InherentIdentifier, Vec<u8>)>,
+
+    /// Hash of the block of the state, which is the parent of the first block produced. Only
+    /// needed for hex snapshots and raw chain specs of a block other than genesis, the hash is
+    /// known for snapshots and nodes.
+    #[clap(long, value_parser = parse::hash)]
+    pub parent_hash: Option<String>,
+}
+
+fn parse_inherent(s: &str) -> Result<(InherentIdentifier, Vec<u8>), String> {
+    let (id, data) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected identifier=0x<data>, found {}", s))?;
+    let id: InherentIdentifier = id
+        .as_bytes()
+        .try_into()
+        .map_err(|_| format!("Inherent identifier must be 8 bytes long: {}", id))?;
+    let data = hex::decode(data.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid hex inherent data {}: {}", data, e))?;
+
+    Ok((id, data))
+}
+
+/// Execute a runtime api and apply the storage changes to `ext`.
+pub fn call_and_apply<Block, HostFns>(
+    ext: &mut TestExternalities<HashingFor<Block>>,
+    executor: &WasmExecutor<HostFns>,
+    method: &'static str,
+    data: &[u8],
+) -> sc_cli::Result<Vec<u8>>
+where
+    Block: BlockT,
+    HostFns: HostFunctions,
+{
+    let (mut changes, encoded) =
+        state_machine_call(ext, executor, method, data, Default::default())?;
+    let storage_changes = changes
+        .drain_storage_changes(&ext.backend, ext.state_version)
+        .map_err(|e| format!("Failed to apply storage changes of {}: {}", method, e))?;
+    ext.backend.apply_transaction(
+        storage_changes.transaction_storage_root,
+        storage_changes.transaction,
+    );
+
+    Ok(encoded)
+}
+
+/// Inherent data passed by the user, which is already SCALE encoded.
+struct AlreadyEncoded<'a>(&'a [u8]);
+
+impl Encode for AlreadyEncoded<'_> {
+    fn encode_to<T: Output + ?Sized>(&self, dest: &mut T) {
+        dest.write(self.0)
+    }
+}
+
+/// Read and decode a storage value.
+fn get<Block: BlockT, T: Decode>(
+    ext: &mut TestExternalities<HashingFor<Block>>,
+    key: &[u8],
+) -> Option<T> {
+    ext.execute_with(|| sp_io::storage::get(key))
+        .and_then(|value| T::decode(&mut &value[..]).ok())
+}
+
+/// Builds blocks one after the other on top of the state, like a collator with no transactions.
+pub struct BlockProducer<Block: BlockT, HostFns: HostFunctions> {
+    pub ext: TestExternalities<HashingFor<Block>>,
+    executor: WasmExecutor<HostFns>,
+    config: BlockConfig,
+    parent_hash: Block::Hash,
+    number: NumberFor<Block>,
+    /// Timestamp of the last block, in milliseconds
+    timestamp: u64,
+    para_id: u32,
+    relay_parent_number: u32,
+}
+
+impl<Block: BlockT, HostFns: HostFunctions> BlockProducer<Block, HostFns> {
+    /// `block_hash` is the hash of the block of the state, if known.
+    pub fn new(
+        shared: &SharedParams,
+        mut ext: TestExternalities<HashingFor<Block>>,
+        block_hash: Option<Block::Hash>,
+        config: BlockConfig,
+    ) -> sc_cli::Result<Self> {
+        let number: NumberFor<Block> =
+            get::<Block, _>(&mut ext, &storage_prefix("System", "Number")).unwrap_or_default();
+        // `System::BlockHash` only has the hashes of the ancestors of the block
+        let parent_hash = match (&config.parent_hash, block_hash) {
+            (Some(hash), _) => {
+                let encoded = hex::decode(hash).map_err(|e| e.to_string())?;
+                Block::Hash::decode_all(&mut &encoded[..])
+                    .map_err(|_| format!("Invalid block hash: 0x{}", hash))?
+            }
+            (None, Some(hash)) => hash,
+            (None, None) if number.is_zero() => {
+                ext.commit_all()?;
+                // Same as the genesis block built by the node from a chain spec
+                let genesis = Block::Header::new(
+                    Zero::zero(),
+                    HashingFor::<Block>::trie_root(vec![], ext.state_version),
+                    *ext.backend.root(),
+                    Default::default(),
+                    Default::default(),
+                );
+                genesis.hash()
+            }
+            (None, None) => {
+                return Err(format!(
+                    "The hash of block #{} is not known, pass it with --parent-hash",
+                    number
+                )
+                .into())
+            }
+        };
+        let timestamp = get::<Block, u64>(&mut ext, &storage_prefix("Timestamp", "Now"))
+            .unwrap_or_else(|| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_millis() as u64;
+                now - config.block_time
+            });
+        let para_id = if config.parachain {
+            get::<Block, u32>(&mut ext, &storage_prefix("ParachainInfo", "ParachainId"))
+                .ok_or("ParachainInfo::ParachainId not found in state, remove --parachain")?
+        } else {
+            0
+        };
+        let relay_parent_number = get::<Block, u32>(
+            &mut ext,
+            &storage_prefix("ParachainSystem", "LastRelayChainBlockNumber"),
+        )
+        .unwrap_or_default();
+
+        Ok(Self {
+            ext,
+            executor: build_executor::<HostFns>(shared),
+            config,
+            parent_hash,
+            number,
+            timestamp,
+            para_id,
+            relay_parent_number,
+        })
+    }
+
+    fn inherent_data(&self) -> Result<InherentData, sp_inherents::Error> {
+        let mut data = InherentData::new();
+        data.put_data(TIMESTAMP_INHERENT_IDENTIFIER, &self.timestamp)?;
+
+        if self.config.parachain {
+            let mut sproof = RelayStateSproofBuilder::default();
+            sproof.para_id = self.para_id.into();
+            sproof.current_slot = (self.timestamp / RELAY_SLOT_DURATION).into();
+            let (relay_parent_storage_root, relay_chain_state) = sproof.into_state_root_and_proof();
+            let parachain_data = ParachainInherentData {
+                validation_data: PersistedValidationData {
+                    parent_head: Default::default(),
+                    relay_parent_number: self.relay_parent_number,
+                    relay_parent_storage_root,
+                    max_pov_size: 5 * 1024 * 1024,
+                },
+                relay_chain_state,
+                downward_messages: Default::default(),
+                horizontal_messages: Default::default(),
+            };
+            data.put_data(PARACHAIN_INHERENT_IDENTIFIER, &parachain_data)?;
+        }
+        for (id, value) in &self.config.inherents {
+            data.put_data(*id, &AlreadyEncoded(value))?;
+        }
+
+        Ok(data)
+    }
+
+    /// Produce the next block and return its header.
+    pub fn produce_block(&mut self) -> sc_cli::Result<Block::Header> {
+        self.number += One::one();
+        self.timestamp += self.config.block_time;
+        self.relay_parent_number += 1;
+
+        let mut digest = Digest::default();
+        if self.config.aura {
+            let slot = self.timestamp / self.config.block_time;
+            digest
+                .logs
+                .push(DigestItem::PreRuntime(AURA_ENGINE_ID, slot.encode()));
+        }
+        let header = Block::Header::new(
+            self.number,
+            Default::default(),
+            Default::default(),
+            self.parent_hash,
+            digest,
+        );
+
+        call_and_apply::<Block, HostFns>(
+            &mut self.ext,
+            &self.executor,
+            "Core_initialize_block",
+            &header.encode(),
+        )?;
+
+        let inherent_data = self
+            .inherent_data()
+            .map_err(|e| format!("Failed to create inherent data: {}", e))?;
+        let encoded = call_and_apply::<Block, HostFns>(
+            &mut self.ext,
+            &self.executor,
+            "BlockBuilder_inherent_extrinsics",
+            &inherent_data.encode(),
+        )?;
+        let inherents = Vec::<Block::Extrinsic>::decode(&mut &encoded[..])?;
+        for (i, xt) in inherents.iter().enumerate() {
+            let encoded = call_and_apply::<Block, HostFns>(
+                &mut self.ext,
+                &self.executor,
+                "BlockBuilder_apply_extrinsic",
+                &xt.encode(),
+            )?;
+            match ApplyExtrinsicResult::decode(&mut &encoded[..])? {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => return Err(format!("Inherent {} failed: {:?}", i, e).into()),
+                Err(e) => return Err(format!("Inherent {} is invalid: {:?}", i, e).into()),
+            }
+        }
+
+        let encoded = call_and_apply::<Block, HostFns>(
+            &mut self.ext,
+            &self.executor,
+            "BlockBuilder_finalize_block",
+            &[],
+        )?;
+        let header = Block::Header::decode(&mut &encoded[..])?;
+        self.parent_hash = header.hash();
+
+        Ok(header)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::metadata::{load_runtime_info, runtime_code};
+use crate::parse;
+use crate::scale_json::decode_all;
+use crate::state_source::{open_source, RpcConfig};
+use crate::to_hex_snap::keep_prefixes;
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use sp_runtime::traits::NumberFor;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`check_decode`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct CheckDecodeCommand {
+    /// A pallet to check. Can be provided multiple times. If empty, entire chain state will
+    /// be checked.
+    #[arg(short, long, num_args = 1..)]
+    pub pallet: Vec<String>,
+
+    /// Storage entry key prefixes to check. Pass as 0x prefixed hex strings. By default, all
+    /// keys are checked.
+    #[arg(long, value_parser = parse::hash, num_args = 1..)]
+    pub prefix: Vec<String>,
+
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to read. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Number of failed keys to show for each storage item
+    #[clap(long, default_value_t = 3)]
+    pub samples: usize,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// Decoding results of one storage item.
+#[derive(Debug, Default)]
+struct ItemReport {
+    keys: u64,
+    failed: u64,
+    /// Failed keys with their error
+    samples: Vec<(Vec<u8>, String)>,
+}
+
+pub async fn check_decode<Block, HostFns>(
+    shared: SharedParams,
+    command: CheckDecodeCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let keep_prefixes = keep_prefixes(command.prefix, command.pallet);
+
+    let input = open_source::<Block>(&command.state_path, &command.rpc).await?;
+    let code = runtime_code(&shared, || input.code())?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    let mut report: BTreeMap<String, ItemReport> = BTreeMap::new();
+    let mut count_unknown = 0u64;
+    for kv in input.key_values_with_prefixes(keep_prefixes) {
+        let (key, value) = kv?;
+        let Some(item) = runtime.storage_item_of_key(&key) else {
+            // Well known keys and orphaned keys have no type
+            count_unknown += 1;
+            continue;
+        };
+
+        let item_report = report.entry(item.to_string()).or_default();
+        item_report.keys += 1;
+        let result = item
+            .decode_key(&runtime.types, &key)
+            .map_err(|e| format!("key: {}", e))
+            .and_then(|_| {
+                decode_all(&runtime.types, item.value_type(), &value)
+                    .map_err(|e| format!("value: {}", e))
+            });
+        if let Err(e) = result {
+            item_report.failed += 1;
+            if item_report.samples.len() < command.samples {
+                item_report.samples.push((key, e));
+            }
+        }
+    }
+
+    let mut count_failed = 0u64;
+    for (item, item_report) in &report {
+        if item_report.failed == 0 {
+            continue;
+        }
+        count_failed += item_report.failed;
+        log::error!(
+            "{}: {} of {} keys failed to decode",
+            item,
+            item_report.failed,
+            item_report.keys
+        );
+        for (key, e) in &item_report.samples {
+            log::error!("    0x{}: {}", hex::encode(key), e);
+        }
+    }
+    log::info!(
+        "Checked {} keys of {} storage items, skipped {} keys without type information",
+        report.values().map(|r| r.keys).sum::<u64>(),
+        report.len(),
+        count_unknown
+    );
+
+    if count_failed > 0 {
+        return Err(format!("{} keys failed to decode", count_failed).into());
+    }
+    log::info!("All keys decoded successfully");
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use crate::should_be_public::{build_executor, state_machine_call};
+use crate::state_file::load_externalities;
+use crate::state_source::RpcConfig;
+use crate::BlockT;
+use frame_support::traits::UpgradeCheckSelect;
+use frame_support::weights::Weight;
+use parity_scale_codec::{Decode, Encode};
+use sc_executor::HostFunctions;
+use sp_runtime::traits::NumberFor;
+use sp_state_machine::Backend;
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`check_state`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct CheckStateCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to check. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Checks to run after the runtime upgrade hooks. `try-state` runs the invariants of all the
+    /// pallets, `pre-and-post` runs the checks of the migrations, `all` runs both.
+    #[clap(long, default_value = "try-state")]
+    pub checks: UpgradeCheckSelect,
+
+    /// Run the checks even if the runtime upgrade hooks change the state. The checks then see
+    /// the migrated state instead of the given one.
+    #[clap(long)]
+    pub allow_migrations: bool,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+pub async fn check_state<Block, HostFns>(
+    shared: SharedParams,
+    command: CheckStateCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let (ext, _) =
+        load_externalities::<Block, HostFns>(&shared, &command.state_path, &command.rpc).await?;
+    let executor = build_executor::<HostFns>(&shared);
+
+    // There is no runtime api that only runs the checks, so the migrations of the runtime run
+    // first. The state is not written, but the checks see the migrated state.
+    log::info!(
+        "Running TryRuntime_on_runtime_upgrade with checks {:?}",
+        command.checks
+    );
+    // The runtime panics when a check fails, and the pallet that failed is in the error message
+    let (mut changes, encoded) = state_machine_call(
+        &ext,
+        &executor,
+        "TryRuntime_on_runtime_upgrade",
+        command.checks.encode().as_slice(),
+        Default::default(),
+    )
+    .inspect_err(|e| {
+        log::error!("State checks failed: {}", e);
+        log::error!("Make sure the runtime was built with the try-runtime feature. Use RUST_LOG=runtime=debug to see the runtime logs");
+    })?;
+
+    let storage_changes = changes
+        .drain_storage_changes(&ext.backend, ext.state_version)
+        .map_err(|e| format!("Failed to read storage changes: {}", e))?;
+    let migrated = storage_changes
+        .main_storage_changes
+        .iter()
+        .filter(|(key, value)| ext.backend.storage(key).ok().flatten() != *value)
+        .collect::<Vec<_>>();
+    if !migrated.is_empty() {
+        for (key, _) in migrated.iter().take(10) {
+            log::warn!(
+                "Changed by the runtime upgrade hooks: 0x{}",
+                hex::encode(key)
+            );
+        }
+        if !command.allow_migrations {
+            return Err(format!(
+                "The runtime upgrade hooks changed {} keys, so the checks ran on migrated state. \
+                 Run the upgrade subcommand first, or pass --allow-migrations",
+                migrated.len()
+            )
+            .into());
+        }
+        log::warn!(
+            "The runtime upgrade hooks changed {} keys, the checks ran on migrated state",
+            migrated.len()
+        );
+    }
+
+    let (weight, max_weight) = <(Weight, Weight)>::decode(&mut &encoded[..])?;
+    log::info!(
+        "State checks passed. Runtime upgrade used weight {:?} of {:?}",
+        weight,
+        max_weight
+    );
+
+    Ok(())
+}
@@ -0,0 +1,191 @@
+//! Compare the storage layout of two runtimes, to detect state that the new runtime will not be
+//! able to decode.
+
+use crate::metadata::{RuntimeInfo, StorageItem};
+use scale_info::form::PortableForm;
+use scale_info::{Field, PortableRegistry, TypeDef};
+use std::collections::HashSet;
+use std::fmt;
+
+/// A storage item that cannot be copied as is from one runtime to the other.
+#[derive(Debug)]
+pub enum Incompatibility {
+    /// The item does not exist in the target runtime
+    Removed(String),
+    /// The item exists but the keys are hashed in a different way
+    ChangedHasher(String),
+    /// The type of some map key is encoded differently
+    ChangedKeyType(String),
+    /// The type of the value is encoded differently
+    ChangedValueType(String),
+    /// The key types cannot be found in the metadata, so they cannot be compared
+    UnknownKeyType(String, String),
+}
+
+impl fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatibility::Removed(item) => write!(f, "{}: removed", item),
+            Incompatibility::ChangedHasher(item) => write!(f, "{}: changed hasher", item),
+            Incompatibility::ChangedKeyType(item) => write!(f, "{}: changed key type", item),
+            Incompatibility::ChangedValueType(item) => write!(f, "{}: changed value type", item),
+            Incompatibility::UnknownKeyType(item, e) => {
+                write!(f, "{}: cannot compare key types: {}", item, e)
+            }
+        }
+    }
+}
+
+/// Returns true if both types have the same SCALE encoding. Names of types, fields and variants
+/// are ignored, only the structure matters.
+pub fn same_encoding(
+    a_types: &PortableRegistry,
+    a: u32,
+    b_types: &PortableRegistry,
+    b: u32,
+    visiting: &mut HashSet<(u32, u32)>,
+) -> bool {
+    // Recursive types: assume equal while comparing them, any difference will be found deeper
+    if !visiting.insert((a, b)) {
+        return true;
+    }
+    let (Some(a_ty), Some(b_ty)) = (a_types.resolve(a), b_types.resolve(b)) else {
+        return false;
+    };
+    match (&a_ty.type_def, &b_ty.type_def) {
+        (TypeDef::Composite(a), TypeDef::Composite(b)) => {
+            same_fields(a_types, &a.fields, b_types, &b.fields, visiting)
+        }
+        (TypeDef::Variant(a), TypeDef::Variant(b)) => {
+            a.variants.len() == b.variants.len()
+                && a.variants.iter().all(|a_variant| {
+                    let b_variant = b.variants.iter().find(|v| v.index == a_variant.index);
+                    b_variant.is_some_and(|b_variant| {
+                        same_fields(
+                            a_types,
+                            &a_variant.fields,
+                            b_types,
+                            &b_variant.fields,
+                            visiting,
+                        )
+                    })
+                })
+        }
+        (TypeDef::Sequence(a), TypeDef::Sequence(b)) => {
+            same_encoding(a_types, a.type_param.id, b_types, b.type_param.id, visiting)
+        }
+        (TypeDef::Array(a), TypeDef::Array(b)) => {
+            a.len == b.len
+                && same_encoding(a_types, a.type_param.id, b_types, b.type_param.id, visiting)
+        }
+        (TypeDef::Tuple(a), TypeDef::Tuple(b)) => {
+            a.fields.len() == b.fields.len()
+                && a.fields
+                    .iter()
+                    .zip(&b.fields)
+                    .all(|(a, b)| same_encoding(a_types, a.id, b_types, b.id, visiting))
+        }
+        (TypeDef::Primitive(a), TypeDef::Primitive(b)) => a == b,
+        (TypeDef::Compact(a), TypeDef::Compact(b)) => {
+            same_encoding(a_types, a.type_param.id, b_types, b.type_param.id, visiting)
+        }
+        (TypeDef::BitSequence(a), TypeDef::BitSequence(b)) => {
+            same_encoding(
+                a_types,
+                a.bit_store_type.id,
+                b_types,
+                b.bit_store_type.id,
+                visiting,
+            ) && same_encoding(
+                a_types,
+                a.bit_order_type.id,
+                b_types,
+                b.bit_order_type.id,
+                visiting,
+            )
+        }
+        // A struct with a single field is encoded as that field
+        (TypeDef::Composite(a), _) if a.fields.len() == 1 => {
+            same_encoding(a_types, a.fields[0].ty.id, b_types, b, visiting)
+        }
+        (_, TypeDef::Composite(b)) if b.fields.len() == 1 => {
+            same_encoding(a_types, a, b_types, b.fields[0].ty.id, visiting)
+        }
+        _ => false,
+    }
+}
+
+fn same_fields(
+    a_types: &PortableRegistry,
+    a_fields: &[Field<PortableForm>],
+    b_types: &PortableRegistry,
+    b_fields: &[Field<PortableForm>],
+    visiting: &mut HashSet<(u32, u32)>,
+) -> bool {
+    a_fields.len() == b_fields.len()
+        && a_fields
+            .iter()
+            .zip(b_fields)
+            .all(|(a, b)| same_encoding(a_types, a.ty.id, b_types, b.ty.id, visiting))
+}
+
+fn compare_item(
+    source: &RuntimeInfo,
+    source_item: StorageItem,
+    target: &RuntimeInfo,
+) -> Option<Incompatibility> {
+    let name = source_item.to_string();
+    let Some(target_item) = target.storage_item_of_key(&source_item.prefix()) else {
+        return Some(Incompatibility::Removed(name));
+    };
+    if source_item.hashers() != target_item.hashers() {
+        return Some(Incompatibility::ChangedHasher(name));
+    }
+    let key_types = source_item
+        .key_types(&source.types)
+        .and_then(|source_keys| Ok((source_keys, target_item.key_types(&target.types)?)));
+    let (source_keys, target_keys) = match key_types {
+        Ok(key_types) => key_types,
+        Err(e) => return Some(Incompatibility::UnknownKeyType(name, e)),
+    };
+    let same_key = |(a, b): (&u32, &u32)| {
+        same_encoding(&source.types, *a, &target.types, *b, &mut HashSet::new())
+    };
+    let same_keys = source_keys.len() == target_keys.len()
+        && source_keys.iter().zip(&target_keys).all(same_key);
+    if !same_keys {
+        return Some(Incompatibility::ChangedKeyType(name));
+    }
+    if !same_encoding(
+        &source.types,
+        source_item.value_type(),
+        &target.types,
+        target_item.value_type(),
+        &mut HashSet::new(),
+    ) {
+        return Some(Incompatibility::ChangedValueType(name));
+    }
+
+    None
+}
+
+/// Compare the storage items of `source` that will be copied into a state with the `target`
+/// runtime. Items are copied if their prefix starts with one of `prefixes`, or if a prefix is
+/// more specific than the item. An empty `prefixes` means all the items.
+pub fn check_compat(
+    source: &RuntimeInfo,
+    target: &RuntimeInfo,
+    prefixes: &[Vec<u8>],
+) -> Vec<Incompatibility> {
+    source
+        .storage_items()
+        .filter(|item| {
+            let item_prefix = item.prefix();
+            prefixes.is_empty()
+                || prefixes
+                    .iter()
+                    .any(|p| item_prefix.starts_with(p) || p.starts_with(&item_prefix))
+        })
+        .filter_map(|item| compare_item(source, item, target))
+        .collect()
+}
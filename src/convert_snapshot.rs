@@ -0,0 +1,211 @@
+use crate::create_snapshot::{hex_param, Rpc};
+use crate::snapshot::{Snapshot, MIN_SNAPSHOT_VERSION, SNAPSHOT_VERSION};
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use std::fmt::Debug;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`convert_snapshot`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct ConvertSnapshotCommand {
+    /// The snapshot to convert, of any supported version.
+    #[clap(long)]
+    pub state_path: PathBuf,
+
+    /// Snapshot version to write, defaults to the version used by `try-runtime`.
+    #[clap(
+        long,
+        default_value_t = SNAPSHOT_VERSION.0,
+        value_parser = clap::value_parser!(u16)
+            .range(MIN_SNAPSHOT_VERSION as i64..=SNAPSHOT_VERSION.0 as i64)
+    )]
+    pub to_version: u16,
+
+    /// The RPC url of a node to download the block header from. Snapshots before version 4 only
+    /// store the block hash, so the header is needed to write a newer version.
+    #[clap(long)]
+    pub uri: Option<String>,
+
+    /// The snapshot path to write.
+    pub snapshot_path: PathBuf,
+}
+
+pub async fn convert_snapshot<Block, HostFns>(
+    _shared: SharedParams,
+    command: ConvertSnapshotCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let mut versioned = Snapshot::<Block>::read_versioned(&command.state_path)?;
+    log::info!(
+        "Read snapshot version {}, writing version {}",
+        versioned.version,
+        command.to_version
+    );
+
+    let block_hash = match versioned.block_hash {
+        Some(block_hash) => {
+            if let Some(uri) = &command.uri {
+                let rpc = Rpc::new(uri, 12)?;
+                let header: Block::Header = rpc.header(&hex_param(block_hash.as_ref())).await?;
+                if header.hash() != block_hash {
+                    return Err(format!(
+                        "Node returned header {:?} for block {:?}",
+                        header.hash(),
+                        block_hash
+                    )
+                    .into());
+                }
+                versioned.snapshot.header = header;
+            } else if command.to_version >= 4 {
+                return Err(
+                    "The snapshot does not store the block header, pass --uri to download it"
+                        .into(),
+                );
+            }
+            block_hash
+        }
+        None => versioned.snapshot.header.hash(),
+    };
+
+    let encoded = versioned
+        .snapshot
+        .encode_version(command.to_version, block_hash)?;
+    log::info!(
+        "Writing snapshot of {} bytes to {}",
+        encoded.len(),
+        command.snapshot_path.display()
+    );
+    fs::write(&command.snapshot_path, encoded)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::prefix_pairs;
+    use crate::{Block, HostFns};
+    use clap::Parser;
+    use jsonrpsee::server::{RpcModule, Server, ServerHandle};
+    use jsonrpsee::types::ErrorObjectOwned;
+    use sp_runtime::{StateVersion, Storage};
+    use std::path::Path;
+
+    type Header = <Block as BlockT>::Header;
+
+    fn snapshot() -> Snapshot<Block> {
+        let top = (1u8..=5).map(|i| (vec![i], vec![i; 40])).collect();
+        let storage = Storage {
+            top,
+            children_default: Default::default(),
+        };
+
+        Snapshot::genesis(storage, StateVersion::V1)
+    }
+
+    /// Node that returns `header` for any block.
+    async fn start_mock(header: Header) -> (String, ServerHandle) {
+        let mut module = RpcModule::new(header);
+        module
+            .register_method("chain_getHeader", |_, header, _| {
+                Ok::<_, ErrorObjectOwned>(header.clone())
+            })
+            .unwrap();
+
+        let server = Server::builder().build("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", server.local_addr().unwrap());
+        (uri, server.start(module))
+    }
+
+    fn command(
+        state_path: &Path,
+        to_version: u16,
+        uri: Option<String>,
+        snapshot_path: &Path,
+    ) -> ConvertSnapshotCommand {
+        ConvertSnapshotCommand {
+            state_path: state_path.to_path_buf(),
+            to_version,
+            uri,
+            snapshot_path: snapshot_path.to_path_buf(),
+        }
+    }
+
+    fn assert_same_state(converted: &Snapshot<Block>, snapshot: &Snapshot<Block>) {
+        assert_eq!(converted.storage_root, snapshot.storage_root);
+        assert_eq!(
+            prefix_pairs::<Block>(&converted.backend(), &[]).unwrap(),
+            prefix_pairs::<Block>(&snapshot.backend(), &[]).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn converts_v2_to_v4_with_header_from_node() {
+        let snapshot = snapshot();
+        let dir = tempfile::tempdir().unwrap();
+        let v2_path = dir.path().join("v2.snap");
+        let v4_path = dir.path().join("v4.snap");
+        let encoded = snapshot.encode_version(2, snapshot.header.hash()).unwrap();
+        fs::write(&v2_path, encoded).unwrap();
+        let (uri, _handle) = start_mock(snapshot.header.clone()).await;
+
+        let shared = SharedParams::parse_from(["snap2zombie"]);
+        convert_snapshot::<Block, HostFns>(shared, command(&v2_path, 4, Some(uri), &v4_path))
+            .await
+            .unwrap();
+
+        let converted = Snapshot::<Block>::read_versioned(&v4_path).unwrap();
+        assert_eq!(converted.version, 4);
+        assert_eq!(converted.block_hash, None);
+        assert_eq!(converted.snapshot.header, snapshot.header);
+        assert_same_state(&converted.snapshot, &snapshot);
+    }
+
+    #[tokio::test]
+    async fn converts_v4_to_v3() {
+        let snapshot = snapshot();
+        let dir = tempfile::tempdir().unwrap();
+        let v4_path = dir.path().join("v4.snap");
+        let v3_path = dir.path().join("v3.snap");
+        snapshot.write(&v4_path).unwrap();
+
+        let shared = SharedParams::parse_from(["snap2zombie"]);
+        convert_snapshot::<Block, HostFns>(shared, command(&v4_path, 3, None, &v3_path))
+            .await
+            .unwrap();
+
+        let converted = Snapshot::<Block>::read_versioned(&v3_path).unwrap();
+        assert_eq!(converted.version, 3);
+        assert_eq!(converted.block_hash, Some(snapshot.header.hash()));
+        assert_same_state(&converted.snapshot, &snapshot);
+    }
+
+    #[tokio::test]
+    async fn requires_uri_to_write_header() {
+        let snapshot = snapshot();
+        let dir = tempfile::tempdir().unwrap();
+        let v3_path = dir.path().join("v3.snap");
+        let v4_path = dir.path().join("v4.snap");
+        let encoded = snapshot.encode_version(3, snapshot.header.hash()).unwrap();
+        fs::write(&v3_path, encoded).unwrap();
+
+        let shared = SharedParams::parse_from(["snap2zombie"]);
+        let result =
+            convert_snapshot::<Block, HostFns>(shared, command(&v3_path, 4, None, &v4_path)).await;
+
+        assert!(result.unwrap_err().to_string().contains("--uri"));
+        assert!(!v4_path.exists());
+    }
+}
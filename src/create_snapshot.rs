@@ -0,0 +1,772 @@
+//! Download the state of a live chain into a snapshot. Key-values are appended to a checkpoint
+//! file as they are downloaded, so an interrupted download can be resumed.
+
+use crate::parse;
+use crate::snapshot::Snapshot;
+use crate::state_file::{format_kv, key_values};
+use crate::to_hex_snap::keep_prefixes;
+use crate::BlockT;
+use jsonrpsee::core::client::ClientT;
+use jsonrpsee::core::params::ArrayParams;
+use jsonrpsee::http_client::{HttpClient, HttpClientBuilder};
+use jsonrpsee::rpc_params;
+use sc_executor::HostFunctions;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sp_runtime::app_crypto::sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_runtime::app_crypto::sp_core::storage::ChildInfo;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use sp_runtime::{StateVersion, Storage, StorageChild};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`create_snapshot`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct CreateSnapshotCommand {
+    /// The RPC url of the node. `ws://` and `wss://` are replaced with `http://` and `https://`.
+    #[clap(long, default_value = "ws://127.0.0.1:9944")]
+    pub uri: String,
+
+    /// The block hash at which to fetch the state. Defaults to the latest finalized head, or to
+    /// the block of the checkpoint when resuming.
+    #[arg(long, value_parser = parse::hash)]
+    pub at: Option<String>,
+
+    /// A pallet to download. Can be provided multiple times. If empty, entire chain state will
+    /// be downloaded.
+    #[arg(short, long, num_args = 1..)]
+    pub pallet: Vec<String>,
+
+    /// Storage entry key prefixes to download. Pass as 0x prefixed hex strings. By default, all
+    /// keys are downloaded.
+    #[arg(long, value_parser = parse::hash, num_args = 1..)]
+    pub prefix: Vec<String>,
+
+    /// Number of keys to fetch in each request.
+    #[clap(long, default_value_t = 1000)]
+    pub batch_size: u32,
+
+    /// Number of times to retry a failed request before giving up.
+    #[clap(long, default_value_t = 12)]
+    pub retries: u32,
+
+    /// Directory to store the progress. Defaults to the snapshot path with `.checkpoint`
+    /// appended. Run the same command again to resume.
+    #[clap(long)]
+    pub checkpoint_dir: Option<PathBuf>,
+
+    /// Keep the checkpoint directory after the snapshot is written. It contains a hex snapshot
+    /// of the downloaded state.
+    #[clap(long)]
+    pub keep_checkpoint: bool,
+
+    /// The snapshot path to write.
+    pub snapshot_path: PathBuf,
+}
+
+/// What is being downloaded, to avoid resuming a checkpoint of a different download.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct CheckpointMeta {
+    at: String,
+    prefixes: Vec<String>,
+}
+
+/// HTTP JSON-RPC client that retries failed requests.
+pub struct Rpc {
+    client: HttpClient,
+    retries: u32,
+}
+
+impl Rpc {
+    /// `ws://` and `wss://` urls are replaced with `http://` and `https://`, because ws is not
+    /// stable for fetching big responses.
+    pub fn new(uri: &str, retries: u32) -> Result<Self, String> {
+        let uri = uri
+            .replacen("wss://", "https://", 1)
+            .replacen("ws://", "http://", 1);
+        let client = HttpClientBuilder::default()
+            .max_request_size(u32::MAX)
+            .max_response_size(u32::MAX)
+            .request_timeout(Duration::from_secs(60 * 5))
+            .build(&uri)
+            .map_err(|e| format!("Failed to connect to {}: {}", uri, e))?;
+
+        Ok(Self { client, retries })
+    }
+
+    pub async fn request<R: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: ArrayParams,
+    ) -> Result<R, String> {
+        let mut attempt = 0;
+        loop {
+            match self.client.request::<R, _>(method, params.clone()).await {
+                Ok(res) => return Ok(res),
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Request {} failed ({}/{} retries). Error: {}",
+                        method,
+                        attempt,
+                        self.retries,
+                        e
+                    );
+                    tokio::time::sleep(Duration::from_secs(attempt.min(10) as u64)).await;
+                }
+                Err(e) => return Err(format!("Request {} failed: {}", method, e)),
+            }
+        }
+    }
+
+    pub async fn finalized_head(&self) -> Result<String, String> {
+        self.request("chain_getFinalizedHead", rpc_params![]).await
+    }
+
+    pub async fn header<H: DeserializeOwned>(&self, at: &str) -> Result<H, String> {
+        self.request("chain_getHeader", rpc_params![at]).await
+    }
+
+    /// State version of the runtime at block `at`.
+    pub async fn state_version(&self, at: &str) -> Result<StateVersion, String> {
+        let version: Value = self
+            .request("state_getRuntimeVersion", rpc_params![at])
+            .await?;
+        Ok(match version.get("stateVersion").and_then(|v| v.as_u64()) {
+            Some(0) => StateVersion::V0,
+            _ => StateVersion::V1,
+        })
+    }
+
+    /// Download one page of key-values under `prefix`, after `start_key`. Also returns the
+    /// `start_key` of the next page, or `None` if this is the last page.
+    pub async fn fetch_page(
+        &self,
+        prefix: &[u8],
+        start_key: Option<&[u8]>,
+        batch_size: u32,
+        at: &str,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>), String> {
+        let keys: Vec<String> = self
+            .request(
+                "state_getKeysPaged",
+                rpc_params![hex_param(prefix), batch_size, start_key.map(hex_param), at],
+            )
+            .await?;
+        let Some(last) = keys.last() else {
+            return Ok((vec![], None));
+        };
+        let next_start_key = (keys.len() == batch_size as usize)
+            .then(|| parse_hex(last))
+            .transpose()?;
+
+        let result: Value = self
+            .request("state_queryStorageAt", rpc_params![&keys, at])
+            .await?;
+        let mut values: BTreeMap<Vec<u8>, Option<Vec<u8>>> =
+            parse_storage_changes(&result)?.into_iter().collect();
+        let mut key_values = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let key = parse_hex(key)?;
+            match values.remove(&key).flatten() {
+                Some(value) => key_values.push((key, value)),
+                None => log::warn!("Key {} has no value", hex_param(&key)),
+            }
+        }
+
+        Ok((key_values, next_start_key))
+    }
+
+    /// Download one page of key-values of the child trie at `child_key`, which is the key of its
+    /// root in the top trie. Also returns the `start_key` of the next page, like
+    /// [`Self::fetch_page`].
+    pub async fn fetch_child_page(
+        &self,
+        child_key: &[u8],
+        start_key: Option<&[u8]>,
+        batch_size: u32,
+        at: &str,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Option<Vec<u8>>), String> {
+        let keys: Vec<String> = self
+            .request(
+                "childstate_getKeysPaged",
+                rpc_params![
+                    hex_param(child_key),
+                    hex_param(&[]),
+                    batch_size,
+                    start_key.map(hex_param),
+                    at
+                ],
+            )
+            .await?;
+        let Some(last) = keys.last() else {
+            return Ok((vec![], None));
+        };
+        let next_start_key = (keys.len() == batch_size as usize)
+            .then(|| parse_hex(last))
+            .transpose()?;
+
+        let mut key_values = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let value: Option<String> = self
+                .request(
+                    "childstate_getStorage",
+                    rpc_params![hex_param(child_key), key, at],
+                )
+                .await?;
+            match value {
+                Some(value) => key_values.push((parse_hex(key)?, parse_hex(&value)?)),
+                None => log::warn!("Child key {} has no value", key),
+            }
+        }
+
+        Ok((key_values, next_start_key))
+    }
+
+    /// Download all the key-values of the child trie at `child_key`.
+    pub async fn fetch_child_trie(
+        &self,
+        child_key: &[u8],
+        batch_size: u32,
+        at: &str,
+    ) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, String> {
+        let mut key_values = BTreeMap::new();
+        let mut start_key = None;
+        loop {
+            let (page, next_start_key) = self
+                .fetch_child_page(child_key, start_key.as_deref(), batch_size, at)
+                .await?;
+            key_values.extend(page);
+            match next_start_key {
+                Some(next_start_key) => start_key = Some(next_start_key),
+                None => return Ok(key_values),
+            }
+        }
+    }
+
+    /// Download all the key-values under `prefix`.
+    pub async fn fetch_prefix(
+        &self,
+        prefix: &[u8],
+        batch_size: u32,
+        at: &str,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        let mut key_values = vec![];
+        let mut start_key = None;
+        loop {
+            let (page, next_start_key) = self
+                .fetch_page(prefix, start_key.as_deref(), batch_size, at)
+                .await?;
+            key_values.extend(page);
+            match next_start_key {
+                Some(next_start_key) => start_key = Some(next_start_key),
+                None => return Ok(key_values),
+            }
+        }
+    }
+}
+
+pub fn hex_param(bytes: &[u8]) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
+
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    hex::decode(s.trim_start_matches("0x")).map_err(|e| format!("Invalid hex {}: {}", s, e))
+}
+
+/// Remove prefixes that are included in other prefixes, and sort them. Then the keys of all the
+/// prefixes are downloaded in order.
+pub fn normalize_prefixes(mut prefixes: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    if prefixes.is_empty() {
+        return vec![vec![]];
+    }
+    prefixes.sort();
+    prefixes.dedup();
+    let mut res: Vec<Vec<u8>> = vec![];
+    for prefix in prefixes {
+        if !res.last().is_some_and(|last| prefix.starts_with(last)) {
+            res.push(prefix);
+        }
+    }
+
+    res
+}
+
+/// Open the checkpoint file for appending, and return the last key that was fully written.
+/// A partially written last line is removed.
+fn open_checkpoint(path: &Path) -> io::Result<(File, Option<Vec<u8>>)> {
+    let mut last_key = None;
+    if path.exists() {
+        let contents = fs::read(path)?;
+        let complete_len = contents
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |pos| pos + 1);
+        if complete_len != contents.len() {
+            log::warn!("Removing incomplete last line of checkpoint");
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(complete_len as u64)?;
+        }
+        for kv in key_values(&path.to_string_lossy())? {
+            last_key = Some(kv?.0);
+        }
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    Ok((file, last_key))
+}
+
+/// Download the child tries of the child storage keys of `top`. Each child trie is saved in the
+/// checkpoint directory once it is complete, and read from there when resuming.
+pub async fn fetch_child_tries(
+    rpc: &Rpc,
+    top: &BTreeMap<Vec<u8>, Vec<u8>>,
+    checkpoint_dir: &Path,
+    batch_size: u32,
+    at: &str,
+) -> sc_cli::Result<HashMap<Vec<u8>, StorageChild>> {
+    let mut children = HashMap::new();
+    let child_keys = top
+        .range(DEFAULT_CHILD_STORAGE_KEY_PREFIX.to_vec()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(DEFAULT_CHILD_STORAGE_KEY_PREFIX));
+    for child_key in child_keys {
+        let storage_key = &child_key[DEFAULT_CHILD_STORAGE_KEY_PREFIX.len()..];
+        let path = checkpoint_dir.join(format!("child-{}.hexsnap.txt", hex::encode(storage_key)));
+        let data = if path.exists() {
+            key_values(&path.to_string_lossy())?.collect::<io::Result<_>>()?
+        } else {
+            let data = rpc.fetch_child_trie(child_key, batch_size, at).await?;
+            // Renamed when complete, so an interrupted child trie is downloaded again
+            let partial_path = path.with_extension("partial");
+            let mut file = BufWriter::new(File::create(&partial_path)?);
+            for (key, value) in &data {
+                writeln!(file, "{},", format_kv(key, value))?;
+            }
+            file.flush()?;
+            drop(file);
+            fs::rename(&partial_path, &path)?;
+            data
+        };
+        log::info!(
+            "Downloaded {} keys of child trie {}",
+            data.len(),
+            hex_param(storage_key)
+        );
+        let child_info = ChildInfo::new_default(storage_key);
+        children.insert(storage_key.to_vec(), StorageChild { data, child_info });
+    }
+
+    Ok(children)
+}
+
+/// Parse the result of `state_queryStorageAt`.
+fn parse_storage_changes(result: &Value) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>, String> {
+    let changes = result
+        .get(0)
+        .and_then(|change_set| change_set.get("changes"))
+        .and_then(|changes| changes.as_array())
+        .ok_or_else(|| format!("Unexpected state_queryStorageAt result: {}", result))?;
+    changes
+        .iter()
+        .map(|change| {
+            let key = change
+                .get(0)
+                .and_then(|k| k.as_str())
+                .ok_or("Missing key")?;
+            let value = match change.get(1).and_then(|v| v.as_str()) {
+                Some(value) => Some(parse_hex(value)?),
+                None => None,
+            };
+            Ok((parse_hex(key)?, value))
+        })
+        .collect()
+}
+
+pub async fn create_snapshot<Block, HostFns>(
+    _shared: SharedParams,
+    command: CreateSnapshotCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let rpc = Rpc::new(&command.uri, command.retries)?;
+
+    let checkpoint_dir = command.checkpoint_dir.clone().unwrap_or_else(|| {
+        let mut dir = command.snapshot_path.clone().into_os_string();
+        dir.push(".checkpoint");
+        dir.into()
+    });
+    fs::create_dir_all(&checkpoint_dir)?;
+    let meta_path = checkpoint_dir.join("meta.json");
+    let old_meta: Option<CheckpointMeta> = match fs::read(&meta_path) {
+        Ok(meta) => Some(
+            serde_json::from_slice(&meta)
+                .map_err(|e| format!("Failed to parse {}: {}", meta_path.display(), e))?,
+        ),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let at = match (&command.at, &old_meta) {
+        (Some(at), _) => format!("0x{}", at),
+        (None, Some(old_meta)) => old_meta.at.clone(),
+        (None, None) => rpc.finalized_head().await?,
+    };
+    let prefixes = normalize_prefixes(keep_prefixes(command.prefix, command.pallet));
+    let meta = CheckpointMeta {
+        at: at.clone(),
+        prefixes: prefixes.iter().map(|p| hex_param(p)).collect(),
+    };
+    match &old_meta {
+        Some(old_meta) if *old_meta != meta => {
+            return Err(format!(
+                "Checkpoint in {} is for a different block or prefixes, delete it to start again",
+                checkpoint_dir.display()
+            )
+            .into());
+        }
+        Some(_) => log::info!("Resuming from checkpoint in {}", checkpoint_dir.display()),
+        None => fs::write(
+            &meta_path,
+            serde_json::to_vec_pretty(&meta).expect("Failed to serialize checkpoint"),
+        )?,
+    }
+    log::info!("Downloading state at block {}", at);
+
+    let checkpoint_path = checkpoint_dir.join("state.hexsnap.txt");
+    let (checkpoint, last_key) = open_checkpoint(&checkpoint_path)?;
+    let mut checkpoint = BufWriter::new(checkpoint);
+    let mut count_keys = 0u64;
+
+    for prefix in &prefixes {
+        // Keys are downloaded in order, so the last key tells where to continue
+        let mut start_key = match &last_key {
+            Some(last_key) if last_key.starts_with(prefix) => Some(last_key.clone()),
+            Some(last_key) if last_key > prefix => {
+                log::info!("Prefix {} already downloaded", hex_param(prefix));
+                continue;
+            }
+            _ => None,
+        };
+
+        loop {
+            let (page, next_start_key) = rpc
+                .fetch_page(prefix, start_key.as_deref(), command.batch_size, &at)
+                .await?;
+            for (key, value) in &page {
+                writeln!(checkpoint, "{},", format_kv(key, value))?;
+            }
+            // Only complete pages are saved, so the checkpoint is consistent if interrupted
+            checkpoint.flush()?;
+            count_keys += page.len() as u64;
+            log::info!("Downloaded {} keys", count_keys);
+
+            match next_start_key {
+                Some(next_start_key) => start_key = Some(next_start_key),
+                None => break,
+            }
+        }
+    }
+    drop(checkpoint);
+
+    let header: Block::Header = rpc.header(&at).await?;
+    let state_version = rpc.state_version(&at).await?;
+
+    let top: BTreeMap<_, _> =
+        key_values(&checkpoint_path.to_string_lossy())?.collect::<io::Result<_>>()?;
+    let children_default =
+        fetch_child_tries(&rpc, &top, &checkpoint_dir, command.batch_size, &at).await?;
+    let storage = Storage {
+        top,
+        children_default,
+    };
+
+    let snapshot = Snapshot::<Block>::from_storage(storage, state_version, header);
+    if snapshot.storage_root != *snapshot.header.state_root() {
+        if prefixes != [vec![]] {
+            log::info!(
+                "Storage root {:?} differs from the header, as expected for a partial state",
+                snapshot.storage_root
+            );
+        } else {
+            return Err(format!(
+                "Downloaded state has storage root {:?} but block has {:?}. The checkpoint in {} \
+                 is kept",
+                snapshot.storage_root,
+                snapshot.header.state_root(),
+                checkpoint_dir.display()
+            )
+            .into());
+        }
+    }
+    snapshot.write(&command.snapshot_path)?;
+    log::info!(
+        "Snapshot has storage root {:?} and state version {:?}",
+        snapshot.storage_root,
+        state_version
+    );
+
+    if !command.keep_checkpoint {
+        fs::remove_dir_all(&checkpoint_dir)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::prefix_pairs;
+    use crate::{Block, HostFns};
+    use clap::Parser;
+    use jsonrpsee::server::{RpcModule, Server, ServerHandle};
+    use jsonrpsee::types::{ErrorObject, ErrorObjectOwned};
+    use sp_state_machine::Backend;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    type Header = <Block as BlockT>::Header;
+
+    const CHILD: &[u8] = b"child";
+
+    /// Node with a single block, whose first `state_queryStorageAt` request fails.
+    struct MockNode {
+        top: BTreeMap<Vec<u8>, Vec<u8>>,
+        child: BTreeMap<Vec<u8>, Vec<u8>>,
+        header: Header,
+        failures: AtomicU32,
+        start_keys: Mutex<Vec<Option<String>>>,
+    }
+
+    fn mock_node() -> MockNode {
+        let top: BTreeMap<_, _> = (1u8..=5).map(|i| (vec![i], vec![i; 3])).collect();
+        let child: BTreeMap<_, _> = (1u8..=3).map(|i| (vec![0xc0, i], vec![i])).collect();
+        let mut children_default = HashMap::new();
+        children_default.insert(
+            CHILD.to_vec(),
+            StorageChild {
+                data: child.clone(),
+                child_info: ChildInfo::new_default(CHILD),
+            },
+        );
+        let genesis = Snapshot::<Block>::genesis(
+            Storage {
+                top,
+                children_default,
+            },
+            StateVersion::V1,
+        );
+
+        MockNode {
+            // Includes the root of the child trie
+            top: prefix_pairs::<Block>(&genesis.backend(), &[])
+                .unwrap()
+                .into_iter()
+                .collect(),
+            child,
+            header: genesis.header,
+            failures: AtomicU32::new(1),
+            start_keys: Mutex::new(vec![]),
+        }
+    }
+
+    fn keys_paged(
+        map: &BTreeMap<Vec<u8>, Vec<u8>>,
+        prefix: &[u8],
+        count: u32,
+        start_key: Option<&str>,
+    ) -> Vec<String> {
+        let start_key = start_key.map(|key| parse_hex(key).unwrap());
+        map.keys()
+            .filter(|key| key.starts_with(prefix))
+            .filter(|key| start_key.as_ref().map_or(true, |start| *key > start))
+            .take(count as usize)
+            .map(|key| hex_param(key))
+            .collect()
+    }
+
+    async fn start_mock(node: Arc<MockNode>) -> (String, ServerHandle) {
+        let mut module = RpcModule::new(node);
+        module
+            .register_method("chain_getFinalizedHead", |_, node, _| {
+                Ok::<_, ErrorObjectOwned>(node.header.hash())
+            })
+            .unwrap();
+        module
+            .register_method("chain_getHeader", |_, node, _| {
+                Ok::<_, ErrorObjectOwned>(node.header.clone())
+            })
+            .unwrap();
+        module
+            .register_method(
+                "state_getRuntimeVersion",
+                |_, _, _| serde_json::json!({ "stateVersion": 1 }),
+            )
+            .unwrap();
+        module
+            .register_method("state_getKeysPaged", |params, node, _| {
+                let mut seq = params.sequence();
+                let prefix: String = seq.next()?;
+                let count: u32 = seq.next()?;
+                let start_key: Option<String> = seq.optional_next()?;
+                node.start_keys.lock().unwrap().push(start_key.clone());
+                let prefix = parse_hex(&prefix).unwrap();
+                Ok::<_, ErrorObjectOwned>(keys_paged(
+                    &node.top,
+                    &prefix,
+                    count,
+                    start_key.as_deref(),
+                ))
+            })
+            .unwrap();
+        module
+            .register_method("state_queryStorageAt", |params, node, _| {
+                if node.failures.load(Ordering::SeqCst) > 0 {
+                    node.failures.fetch_sub(1, Ordering::SeqCst);
+                    return Err(ErrorObject::owned(-32000, "Mock failure", None::<()>));
+                }
+                let keys: Vec<String> = params.sequence().next()?;
+                let changes: Vec<_> = keys
+                    .iter()
+                    .map(|key| {
+                        let value = node.top.get(&parse_hex(key).unwrap());
+                        serde_json::json!([key, value.map(|value| hex_param(value))])
+                    })
+                    .collect();
+                Ok(serde_json::json!([{ "block": node.header.hash(), "changes": changes }]))
+            })
+            .unwrap();
+        module
+            .register_method("childstate_getKeysPaged", |params, node, _| {
+                let mut seq = params.sequence();
+                let _child_key: String = seq.next()?;
+                let _prefix: String = seq.next()?;
+                let count: u32 = seq.next()?;
+                let start_key: Option<String> = seq.optional_next()?;
+                Ok::<_, ErrorObjectOwned>(keys_paged(&node.child, &[], count, start_key.as_deref()))
+            })
+            .unwrap();
+        module
+            .register_method("childstate_getStorage", |params, node, _| {
+                let mut seq = params.sequence();
+                let _child_key: String = seq.next()?;
+                let key: String = seq.next()?;
+                let value = node.child.get(&parse_hex(&key).unwrap());
+                Ok::<_, ErrorObjectOwned>(value.map(|value| hex_param(value)))
+            })
+            .unwrap();
+
+        let server = Server::builder().build("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", server.local_addr().unwrap());
+        (uri, server.start(module))
+    }
+
+    fn command(uri: String, snapshot_path: PathBuf) -> CreateSnapshotCommand {
+        CreateSnapshotCommand {
+            uri,
+            at: None,
+            pallet: vec![],
+            prefix: vec![],
+            batch_size: 2,
+            retries: 1,
+            checkpoint_dir: None,
+            keep_checkpoint: false,
+            snapshot_path,
+        }
+    }
+
+    fn check_snapshot(node: &MockNode, path: &Path) {
+        let snapshot = Snapshot::<Block>::read(path).unwrap();
+        assert_eq!(snapshot.storage_root, *node.header.state_root());
+        assert_eq!(snapshot.header, node.header);
+
+        let backend = snapshot.backend();
+        let top: BTreeMap<_, _> = prefix_pairs::<Block>(&backend, &[])
+            .unwrap()
+            .into_iter()
+            .collect();
+        assert_eq!(top, node.top);
+        let child_info = ChildInfo::new_default(CHILD);
+        for (key, value) in &node.child {
+            let stored = backend.child_storage(&child_info, key).unwrap();
+            assert_eq!(stored.as_ref(), Some(value));
+        }
+    }
+
+    #[tokio::test]
+    async fn downloads_pages_and_child_tries() {
+        let node = Arc::new(mock_node());
+        let (uri, _handle) = start_mock(node.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("state.snap");
+
+        let shared = SharedParams::parse_from(["snap2zombie"]);
+        create_snapshot::<Block, HostFns>(shared, command(uri, snapshot_path.clone()))
+            .await
+            .unwrap();
+
+        check_snapshot(&node, &snapshot_path);
+        // The failed request was retried
+        assert_eq!(node.failures.load(Ordering::SeqCst), 0);
+        // 6 top keys in pages of 2, then an empty page
+        assert_eq!(node.start_keys.lock().unwrap().len(), 4);
+        assert!(!dir.path().join("state.snap.checkpoint").exists());
+    }
+
+    #[tokio::test]
+    async fn resumes_from_checkpoint() {
+        let node = Arc::new(mock_node());
+        let (uri, _handle) = start_mock(node.clone()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("state.snap");
+
+        // Interrupted after the first page, while writing the second one
+        let checkpoint_dir = dir.path().join("state.snap.checkpoint");
+        fs::create_dir(&checkpoint_dir).unwrap();
+        let meta = CheckpointMeta {
+            at: hex_param(node.header.hash().as_ref()),
+            prefixes: vec![hex_param(&[])],
+        };
+        fs::write(
+            checkpoint_dir.join("meta.json"),
+            serde_json::to_vec(&meta).unwrap(),
+        )
+        .unwrap();
+        let mut checkpoint = String::new();
+        for (key, value) in node.top.iter().take(2) {
+            checkpoint.push_str(&format!("{},\n", format_kv(key, value)));
+        }
+        checkpoint.push_str("\"0x03\": \"0x03");
+        fs::write(checkpoint_dir.join("state.hexsnap.txt"), checkpoint).unwrap();
+
+        let shared = SharedParams::parse_from(["snap2zombie"]);
+        create_snapshot::<Block, HostFns>(shared, command(uri, snapshot_path.clone()))
+            .await
+            .unwrap();
+
+        check_snapshot(&node, &snapshot_path);
+        let second_key = node.top.keys().nth(1).unwrap();
+        assert_eq!(
+            node.start_keys.lock().unwrap().first().cloned().flatten(),
+            Some(hex_param(second_key))
+        );
+    }
+}
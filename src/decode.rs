@@ -0,0 +1,131 @@
+use crate::metadata::{load_runtime_info, runtime_code, RuntimeInfo};
+use crate::parse;
+use crate::scale_json::decode_all;
+use crate::state_source::{open_source, RpcConfig};
+use crate::to_hex_snap::keep_prefixes;
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use serde_json::{Map, Value};
+use sp_runtime::traits::NumberFor;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`decode`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct DecodeCommand {
+    /// A pallet to decode. Can be provided multiple times. If empty, entire chain state will
+    /// be decoded.
+    #[arg(short, long, num_args = 1..)]
+    pub pallet: Vec<String>,
+
+    /// Storage entry key prefixes to decode. Pass as 0x prefixed hex strings. By default, all
+    /// keys are decoded.
+    #[arg(long, value_parser = parse::hash, num_args = 1..)]
+    pub prefix: Vec<String>,
+
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to read. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Output path, one JSON object per line. Defaults to stdout.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+fn hex_value(bytes: &[u8]) -> Value {
+    Value::String(format!("0x{}", hex::encode(bytes)))
+}
+
+/// Decode a key-value into a JSON object with `pallet`, `item`, `keys` and `value`. The raw `key`
+/// is always included. Anything that cannot be decoded is left as hex, with an `error`.
+pub fn decode_entry(runtime: &RuntimeInfo, key: &[u8], value: &[u8]) -> Value {
+    let mut entry = Map::new();
+    entry.insert("key".to_string(), hex_value(key));
+
+    let Some(item) = runtime.storage_item_of_key(key) else {
+        entry.insert("value".to_string(), hex_value(value));
+        return Value::Object(entry);
+    };
+    entry.insert("pallet".to_string(), Value::from(item.pallet));
+    entry.insert("item".to_string(), Value::from(item.entry.name.as_str()));
+
+    let mut errors = vec![];
+    match item.decode_key(&runtime.types, key) {
+        Ok(keys) => {
+            entry.insert("keys".to_string(), Value::Array(keys));
+        }
+        Err(e) => errors.push(format!("key: {}", e)),
+    }
+    match decode_all(&runtime.types, item.value_type(), value) {
+        Ok(decoded) => {
+            entry.insert("value".to_string(), decoded);
+        }
+        Err(e) => {
+            entry.insert("value".to_string(), hex_value(value));
+            errors.push(format!("value: {}", e));
+        }
+    }
+    if !errors.is_empty() {
+        entry.insert("error".to_string(), Value::from(errors.join(", ")));
+    }
+
+    Value::Object(entry)
+}
+
+pub async fn decode<Block, HostFns>(
+    shared: SharedParams,
+    command: DecodeCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let keep_prefixes = keep_prefixes(command.prefix, command.pallet);
+
+    let input = open_source::<Block>(&command.state_path, &command.rpc).await?;
+    let code = runtime_code(&shared, || input.code())?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    let mut output: Box<dyn Write> = match command.output_path {
+        Some(output_path) => Box::new(BufWriter::new(File::create(output_path).inspect_err(
+            |e| {
+                log::error!("Failed to create output file: {}", e);
+            },
+        )?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let mut count_decoded = 0u64;
+    let mut count_errors = 0u64;
+    for kv in input.key_values_with_prefixes(keep_prefixes) {
+        let (key, value) = kv?;
+
+        let entry = decode_entry(&runtime, &key, &value);
+        if entry.get("error").is_some() {
+            count_errors += 1;
+        }
+        count_decoded += 1;
+        writeln!(output, "{}", entry)?;
+    }
+    output.flush()?;
+
+    log::info!("Decoded {} keys", count_decoded);
+    if count_errors > 0 {
+        log::warn!("{} keys could not be fully decoded", count_errors);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use crate::metadata::{load_runtime_info, runtime_code, StorageItem};
+use crate::scale_json::encode as encode_value;
+use crate::state_source::{open_sink, open_source, RpcConfig};
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use scale_info::PortableRegistry;
+use serde_json::Value;
+use sp_runtime::traits::NumberFor;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::BufReader;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`encode`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct EncodeCommand {
+    /// JSON file with the state to encode, in the format
+    /// `{"Pallet": {"PlainItem": value, "MapItem": [{"key": key, "value": value}]}}`.
+    #[clap(long)]
+    pub json_path: String,
+
+    /// Snapshot, hex snapshot, raw chain spec or RPC url of a node to read `:code` from, to get
+    /// the metadata. Not needed if `--runtime` is a path.
+    #[clap(long)]
+    pub code_path: Option<String>,
+
+    /// Output path of the hex snapshot.
+    #[clap(long)]
+    pub output_path: String,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// Encode the map keys of one entry. Maps with one hasher take a single `key`, maps with more
+/// hashers take an array. `keys` is also accepted, as an array, which is the output format of the
+/// `decode` subcommand.
+fn encode_map_entry(
+    types: &PortableRegistry,
+    item: StorageItem,
+    entry: &Value,
+) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let keys = match (entry.get("keys"), entry.get("key")) {
+        (Some(Value::Array(keys)), _) => keys.clone(),
+        (None, Some(key)) if item.hashers().len() == 1 => vec![key.clone()],
+        (None, Some(Value::Array(keys))) => keys.clone(),
+        _ => {
+            return Err(format!(
+                "Expected entry of {} as {{\"key\": key, \"value\": value}}, found: {}",
+                item, entry
+            ))
+        }
+    };
+    if keys.len() != item.hashers().len() {
+        return Err(format!(
+            "Storage item {} takes {} keys, got {}",
+            item,
+            item.hashers().len(),
+            keys.len()
+        ));
+    }
+    let value = entry
+        .get("value")
+        .ok_or_else(|| format!("Missing value in entry of {}: {}", item, entry))?;
+
+    Ok((
+        item.key_from_json(types, &keys)?,
+        encode_value(types, item.value_type(), value)?,
+    ))
+}
+
+pub async fn encode<Block, HostFns>(
+    shared: SharedParams,
+    command: EncodeCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let code_source = match &command.code_path {
+        Some(code_path) => Some(open_source::<Block>(code_path, &command.rpc).await?),
+        None => None,
+    };
+    let code = runtime_code(&shared, || match &code_source {
+        Some(code_source) => code_source.code(),
+        None => Ok(None),
+    })?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    let input = File::open(&command.json_path).inspect_err(|e| {
+        log::error!("Failed to open JSON file: {}", e);
+    })?;
+    let state: BTreeMap<String, BTreeMap<String, Value>> =
+        serde_json::from_reader(BufReader::new(input))
+            .map_err(|e| format!("Failed to parse JSON file: {}", e))?;
+
+    let mut key_values = BTreeMap::new();
+    for (pallet, items) in &state {
+        for (item_name, item_value) in items {
+            let item = runtime.storage_item(pallet, item_name)?;
+            if item.hashers().is_empty() {
+                key_values.insert(
+                    item.prefix(),
+                    encode_value(&runtime.types, item.value_type(), item_value)?,
+                );
+                continue;
+            }
+            let entries = item_value.as_array().ok_or_else(|| {
+                format!(
+                    "Storage item {} is a map, expected an array of entries",
+                    item
+                )
+            })?;
+            for entry in entries {
+                let (key, value) = encode_map_entry(&runtime.types, item, entry)?;
+                key_values.insert(key, value);
+            }
+        }
+    }
+
+    let mut sink = open_sink(&command.output_path, None)?;
+    for (key, value) in &key_values {
+        sink.write(key, value)?;
+    }
+    sink.finish()?;
+
+    log::info!("Encoded {} keys", key_values.len());
+
+    Ok(())
+}
@@ -0,0 +1,217 @@
+//! Run the usual `create-snapshot`, `to-hex-snap`, `merge-into-raw` and `pad-with-spaces` steps
+//! in one go. The state is copied into the chain spec as it is read, without writing the
+//! intermediate files.
+
+use crate::merge_into_raw::check_output_size;
+use crate::pad_with_spaces::{pad_with_spaces, PadWithSpacesCommand};
+use crate::prune::{prune_prefixes, InUse, PruneProfile};
+use crate::rebase_time::{rebase_time, RebaseTimeCommand};
+use crate::state_file::{format_kv, merge_key_values};
+use crate::state_source::{open_source, RpcConfig, RpcSource, StateSource};
+use crate::to_hex_snap::keep_prefixes;
+use crate::{parse, BlockT};
+use sc_executor::HostFunctions;
+use sp_runtime::traits::NumberFor;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`fork`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct ForkCommand {
+    /// The RPC url of the node to download the state from. `ws://` and `wss://` are replaced
+    /// with `http://` and `https://`.
+    #[clap(
+        long,
+        conflicts_with = "state_path",
+        required_unless_present = "state_path"
+    )]
+    pub uri: Option<String>,
+
+    /// The snapshot or hex snapshot to read, instead of downloading the state. Snapshots must
+    /// end in `.snap`.
+    #[clap(long)]
+    pub state_path: Option<String>,
+
+    /// The block hash at which to download the state. Defaults to the latest finalized head.
+    #[arg(long, value_parser = parse::hash, requires = "uri")]
+    pub at: Option<String>,
+
+    /// A pallet to copy into the chain spec. Can be provided multiple times.
+    #[arg(short, long, num_args = 1..)]
+    pub pallet: Vec<String>,
+
+    /// Storage entry key prefixes to copy into the chain spec. Pass as 0x prefixed hex strings.
+    #[arg(long, value_parser = parse::hash, num_args = 1..)]
+    pub prefix: Vec<String>,
+
+    /// Remove ALL keys from original chain spec, copy all from the state.
+    #[clap(long)]
+    pub all: bool,
+
+    /// The input chain spec path to read. The chain spec must be in raw format.
+    #[clap(long)]
+    pub chain_spec_path: String,
+
+    /// Output path, defaults to input chain spec path
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    /// Built-in list of storage items to leave out of the copied state, same as `prune
+    /// --profile`. Can be provided multiple times.
+    #[arg(long = "prune", value_enum, num_args = 1..)]
+    pub prune_profile: Vec<PruneProfile>,
+
+    /// Reset the block number and timestamp of the output, same as `rebase-time`.
+    #[clap(long)]
+    pub rebase_time: bool,
+
+    /// Timestamp of the genesis block in milliseconds used by `--rebase-time`, defaults to the
+    /// current time
+    #[clap(long, requires = "rebase_time")]
+    pub genesis_timestamp: Option<u64>,
+
+    /// Slot duration in milliseconds used by `--rebase-time`
+    #[clap(long, default_value_t = 6000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub slot_duration: u64,
+
+    /// Also move the scheduler agendas when using `--rebase-time`
+    #[clap(long, requires = "rebase_time")]
+    pub shift_scheduler: bool,
+
+    /// Pad the output to 2GiB, same as `pad-with-spaces`.
+    #[clap(long)]
+    pub pad: bool,
+
+    /// Number of keys to fetch in each request.
+    #[clap(long, default_value_t = 1000)]
+    pub batch_size: u32,
+
+    /// Number of times to retry a failed request before giving up.
+    #[clap(long, default_value_t = 12)]
+    pub retries: u32,
+
+    /// Also write the copied state as a hex snapshot, to `<output path>.hexsnap.txt`.
+    #[clap(long)]
+    pub keep_intermediate: bool,
+}
+
+pub async fn fork<Block, HostFns>(shared: SharedParams, command: ForkCommand) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let keep_prefixes = keep_prefixes(command.prefix.clone(), command.pallet.clone());
+    if keep_prefixes.is_empty() && !command.all {
+        return Err("Add at least one --pallet arg, or pass --all flag".into());
+    }
+    let pruned = prune_prefixes(&command.prune_profile, &[], &[])?;
+    let output_path = command
+        .output_path
+        .clone()
+        .unwrap_or_else(|| command.chain_spec_path.clone());
+
+    let source_prefixes = if command.all {
+        vec![]
+    } else {
+        keep_prefixes.clone()
+    };
+    let source: Box<dyn StateSource> = match (&command.uri, &command.state_path) {
+        (Some(uri), _) => Box::new(
+            RpcSource::new(uri, command.at.clone(), command.batch_size, command.retries).await?,
+        ),
+        (None, Some(state_path)) => {
+            open_source::<Block>(
+                state_path,
+                &RpcConfig {
+                    batch_size: command.batch_size,
+                    retries: command.retries,
+                },
+            )
+            .await?
+        }
+        (None, None) => return Err("Pass --uri or --state-path".into()),
+    };
+
+    let mut intermediate = if command.keep_intermediate {
+        let path = format!("{}.hexsnap.txt", output_path);
+        log::info!("Writing copied state to {}", path);
+        Some(BufWriter::new(File::create(path)?))
+    } else {
+        None
+    };
+    let in_use = InUse::load(&*source)?;
+    let mut count_pruned = 0u64;
+    let source = source
+        .key_values_with_prefixes(source_prefixes)
+        .filter_map(|kv| match kv {
+            Ok((key, value)) if pruned.iter().any(|(_, p)| key.starts_with(p)) => {
+                match in_use.keep(&key, &value) {
+                    Some(value) => Some(Ok((key, value))),
+                    None => {
+                        count_pruned += 1;
+                        None
+                    }
+                }
+            }
+            kv => Some(kv),
+        })
+        .map(|kv| {
+            let (key, value) = kv?;
+            if let Some(intermediate) = &mut intermediate {
+                writeln!(intermediate, "{},", format_kv(&key, &value))?;
+            }
+            Ok((key, value))
+        });
+
+    // Keys under the prefixes are removed from the chain spec and copied from the state
+    let stats = merge_key_values(
+        &command.chain_spec_path,
+        &output_path,
+        |key| command.all || keep_prefixes.iter().any(|p| key.starts_with(p)),
+        source,
+    )?;
+    if let Some(mut intermediate) = intermediate {
+        intermediate.flush()?;
+    }
+    log::info!("Inserted {} keys into chain spec", stats.inserted);
+    if count_pruned > 0 {
+        log::info!("Pruned {} keys", count_pruned);
+    }
+
+    if command.rebase_time {
+        let rebase = RebaseTimeCommand {
+            state_path: output_path.clone(),
+            output_path: None,
+            genesis_timestamp: command.genesis_timestamp,
+            slot_duration: command.slot_duration,
+            shift_scheduler: command.shift_scheduler,
+            rpc: RpcConfig {
+                batch_size: command.batch_size,
+                retries: command.retries,
+            },
+        };
+        rebase_time::<Block, HostFns>(shared.clone(), rebase).await?;
+    }
+
+    if command.pad {
+        let pad = PadWithSpacesCommand {
+            chain_spec_path: output_path,
+            output_path: None,
+            ascii_code: None,
+            target_size: None,
+        };
+        pad_with_spaces::<Block, HostFns>(shared, pad).await?;
+    } else {
+        check_output_size(&output_path)?;
+    }
+
+    Ok(())
+}
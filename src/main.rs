@@ -1,8 +1,32 @@
+use crate::apply_calls::{apply_calls, ApplyCallsCommand};
+use crate::check_decode::{check_decode, CheckDecodeCommand};
+#[cfg(feature = "try-runtime")]
+use crate::check_state::{check_state, CheckStateCommand};
+use crate::convert_snapshot::{convert_snapshot, ConvertSnapshotCommand};
+use crate::create_snapshot::{create_snapshot, CreateSnapshotCommand};
+use crate::decode::{decode, DecodeCommand};
+use crate::encode::{encode, EncodeCommand};
+use crate::fork::{fork, ForkCommand};
 use crate::merge_into_raw::{merge_into_raw, MergeIntoRawCommand};
+use crate::orphans::{orphans, OrphansCommand};
 use crate::pad_with_spaces::{pad_with_spaces, PadWithSpacesCommand};
+use crate::prune::{prune, PruneCommand};
+use crate::query::{query, QueryCommand};
+use crate::rebase_time::{rebase_time, RebaseTimeCommand};
+use crate::refresh_snapshot::{refresh_snapshot, RefreshSnapshotCommand};
+use crate::runtime_info::{dump_metadata, runtime_version, MetadataCommand, RuntimeVersionCommand};
+use crate::serve::{serve, ServeCommand};
+use crate::set_storage::{remove_storage, set_storage, RemoveStorageCommand, SetStorageCommand};
 use crate::should_be_public::parse;
+use crate::size_report::{size_report, SizeReportCommand};
+use crate::smoke_test::{smoke_test, SmokeTestCommand};
+use crate::snapshot_from_db::{snapshot_from_db, SnapshotFromDbCommand};
+use crate::spec_to_snap::{spec_to_snap, SpecToSnapCommand};
+use crate::substitute_account::{substitute_account, SubstituteAccountCommand};
 use crate::to_hex_snap::to_hex_snap;
 use crate::to_hex_snap::ToHexSnapCommand;
+use crate::to_snap::{to_snap, ToSnapCommand};
+use crate::upgrade::{upgrade, UpgradeCommand};
 use clap::Parser;
 use sc_executor::sp_wasm_interface::HostFunctions;
 use serde::de::DeserializeOwned;
@@ -17,13 +41,43 @@ use sp_runtime::{
 use std::env;
 use std::fmt::Debug;
 use std::str::FromStr;
-use try_runtime_core::commands::create_snapshot;
 use try_runtime_core::common::shared_parameters::SharedParams;
 
+mod apply_calls;
+mod block_builder;
+mod check_decode;
+#[cfg(feature = "try-runtime")]
+mod check_state;
+mod compat;
+mod convert_snapshot;
+mod create_snapshot;
+mod decode;
+mod encode;
+mod fork;
 mod merge_into_raw;
+mod metadata;
+mod orphans;
 mod pad_with_spaces;
+mod prune;
+mod query;
+mod rebase_time;
+mod refresh_snapshot;
+mod runtime_info;
+mod scale_json;
+mod serve;
+mod set_storage;
 mod should_be_public;
+mod size_report;
+mod smoke_test;
+mod snapshot;
+mod snapshot_from_db;
+mod spec_to_snap;
+mod state_file;
+mod state_source;
+mod substitute_account;
 mod to_hex_snap;
+mod to_snap;
+mod upgrade;
 
 type Block = BlockGeneric<Header<u32, BlakeTwo256>, OpaqueExtrinsic>;
 type HostFns = sp_io::SubstrateHostFunctions;
@@ -37,9 +91,58 @@ pub enum Action {
     MergeIntoRaw(MergeIntoRawCommand),
     /// Increase size of a file by padding with a single byte
     PadWithSpaces(PadWithSpacesCommand),
-    /// Re-export of create-snapshot command from try-runtime, to avoid an extra cargo install if
-    /// the user does not have try-runtime already installed.
-    CreateSnapshot(create_snapshot::Command),
+    /// Set the value of a storage item in a hex snapshot or raw chain spec, using the metadata to
+    /// encode the key and value
+    SetStorage(SetStorageCommand),
+    /// Remove one entry or a whole storage map from a hex snapshot or raw chain spec
+    RemoveStorage(RemoveStorageCommand),
+    /// Replace an account with another one in all the storage keys and values
+    SubstituteAccount(SubstituteAccountCommand),
+    /// Remove storage items that are not needed for tests, to reduce the chain spec size
+    Prune(PruneCommand),
+    /// Reset block number, block hashes, timestamp and slot to be consistent with a new genesis
+    RebaseTime(RebaseTimeCommand),
+    /// Decode the keys and values of a snapshot or hex snapshot into JSON
+    Decode(DecodeCommand),
+    /// Read and decode a single storage value, or all the entries of a map
+    Query(QueryCommand),
+    /// Encode human-readable JSON state into a hex snapshot
+    Encode(EncodeCommand),
+    /// Show which pallets and storage items take the most space
+    SizeReport(SizeReportCommand),
+    /// Find keys that do not belong to any storage item of the runtime
+    Orphans(OrphansCommand),
+    /// Print the metadata of the runtime in `:code`
+    Metadata(MetadataCommand),
+    /// Print the version of the runtime in `:code`
+    RuntimeVersion(RuntimeVersionCommand),
+    /// Check that all the keys and values can be decoded with the types of the runtime
+    CheckDecode(CheckDecodeCommand),
+    /// Run the try-state checks of all the pallets against the state
+    #[cfg(feature = "try-runtime")]
+    CheckState(CheckStateCommand),
+    /// Produce blocks on top of the state to check that the chain does not stall
+    SmokeTest(SmokeTestCommand),
+    /// Replace the runtime and run its migrations, including multi-block migrations
+    Upgrade(UpgradeCommand),
+    /// Dispatch calls with a privileged origin using the scheduler
+    ApplyCalls(ApplyCallsCommand),
+    /// Download the state of a live chain into a snapshot, with checkpoints to resume the download
+    CreateSnapshot(CreateSnapshotCommand),
+    /// Update a snapshot to a newer block, only downloading the storage items that changed
+    RefreshSnapshot(RefreshSnapshotCommand),
+    /// Read the state from the database of a local node into a snapshot or hex snapshot
+    SnapshotFromDb(SnapshotFromDbCommand),
+    /// Convert a raw chain spec into a snapshot of its genesis block
+    SpecToSnap(SpecToSnapCommand),
+    /// Write a snapshot from a hex snapshot, or a snapshot with only some pallets
+    ToSnap(ToSnapCommand),
+    /// Copy the state of a live chain or a snapshot into a raw chain spec in one step
+    Fork(ForkCommand),
+    /// Serve the storage of a snapshot over JSON-RPC, like a node stuck at one block
+    Serve(ServeCommand),
+    /// Rewrite a snapshot in the layout of another snapshot version
+    ConvertSnapshot(ConvertSnapshotCommand),
 }
 
 impl Action {
@@ -63,8 +166,81 @@ impl Action {
             Action::PadWithSpaces(cmd) => {
                 pad_with_spaces::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
             }
+            Action::SetStorage(cmd) => {
+                set_storage::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::RemoveStorage(cmd) => {
+                remove_storage::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::SubstituteAccount(cmd) => {
+                substitute_account::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Prune(cmd) => {
+                prune::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::RebaseTime(cmd) => {
+                rebase_time::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Decode(cmd) => {
+                decode::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Query(cmd) => {
+                query::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Encode(cmd) => {
+                encode::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::SizeReport(cmd) => {
+                size_report::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Orphans(cmd) => {
+                orphans::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Metadata(cmd) => {
+                dump_metadata::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::RuntimeVersion(cmd) => {
+                runtime_version::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::CheckDecode(cmd) => {
+                check_decode::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            #[cfg(feature = "try-runtime")]
+            Action::CheckState(cmd) => {
+                check_state::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::SmokeTest(cmd) => {
+                smoke_test::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Upgrade(cmd) => {
+                upgrade::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::ApplyCalls(cmd) => {
+                apply_calls::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
             Action::CreateSnapshot(cmd) => {
-                create_snapshot::run::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+                create_snapshot::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::RefreshSnapshot(cmd) => {
+                refresh_snapshot::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::SnapshotFromDb(cmd) => {
+                snapshot_from_db::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::SpecToSnap(cmd) => {
+                spec_to_snap::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::ToSnap(cmd) => {
+                to_snap::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Fork(cmd) => {
+                fork::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::Serve(cmd) => {
+                serve::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
+            }
+            Action::ConvertSnapshot(cmd) => {
+                convert_snapshot::<Block, HostFns>(shared.clone(), cmd.clone()).await?;
             }
         }
 
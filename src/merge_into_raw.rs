@@ -1,19 +1,20 @@
+use crate::compat::check_compat as check_compat_items;
+use crate::metadata::{load_runtime_info, runtime_code, CODE_KEY};
 use crate::parse;
 use crate::should_be_public::build_executor;
+use crate::state_file::{find_code, merge_key_values};
+use crate::state_source::{open_source, RpcConfig, StateSource};
 use crate::to_hex_snap::{storage_iter, ToHexSnapCommand};
 use crate::BlockT;
 use frame_remote_externalities::RemoteExternalities;
-use regex::Regex;
 use sc_executor::HostFunctions;
 use sp_runtime::app_crypto::sp_core::twox_128;
 use sp_runtime::traits::NumberFor;
 use std::fmt::Debug;
-use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::Path;
+use std::io;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::{fs, mem};
-use tempfile::NamedTempFile;
 use try_runtime_core::common::shared_parameters::SharedParams;
 use try_runtime_core::common::state::{RuntimeChecks, State};
 
@@ -37,6 +38,7 @@ pub struct MergeIntoRawCommand {
     pub chain_spec_path: String,
 
     /// The snapshot path to read. Must be in hex format, the output of the [`to_json`]  subcommand.
+    /// Can also be a snapshot, a raw chain spec or the RPC url of a node.
     #[clap(long)]
     pub hex_snapshot_path: String,
 
@@ -47,6 +49,80 @@ pub struct MergeIntoRawCommand {
     /// Remove ALL keys from original chain spec, copy all from the snapshot.
     #[clap(long)]
     pub all: bool,
+
+    /// Before merging, compare the storage items that will be copied in the metadata of the
+    /// snapshot runtime and the chain spec runtime, and abort if some of them changed.
+    #[clap(long)]
+    pub check_compat: bool,
+
+    /// Runtime wasm of the snapshot, used by `--check-compat`. Defaults to the `:code` of the hex
+    /// snapshot.
+    #[clap(long)]
+    pub source_runtime: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// Compare the storage layout of the runtime of the snapshot with the runtime of the chain spec.
+fn check_compat<HostFns: HostFunctions>(
+    shared: &SharedParams,
+    command: &MergeIntoRawCommand,
+    patch: &dyn StateSource,
+    keep_prefixes: &[Vec<u8>],
+) -> Result<(), String> {
+    // The merged state would run the snapshot runtime, not the one the items are compared with
+    if keep_prefixes.is_empty() || keep_prefixes.iter().any(|p| CODE_KEY.starts_with(p)) {
+        return Err(
+            "--check-compat compares with the chain spec runtime, but the merge replaces :code \
+             with the snapshot runtime"
+                .to_string(),
+        );
+    }
+    let source_code = match &command.source_runtime {
+        Some(path) => fs::read(path)
+            .map_err(|e| format!("Failed to read runtime from {}: {}", path.display(), e))?,
+        None => patch
+            .code()
+            .map_err(|e| format!("Failed to read :code from hex snapshot: {}", e))?
+            .ok_or_else(|| {
+                "Hex snapshot does not contain :code, pass the runtime wasm with --source-runtime"
+                    .to_string()
+            })?,
+    };
+    let target_code = runtime_code(shared, || find_code(&command.chain_spec_path))?;
+    let source = load_runtime_info::<HostFns>(shared, &source_code)?;
+    let target = load_runtime_info::<HostFns>(shared, &target_code)?;
+
+    let incompatible = check_compat_items(&source, &target, keep_prefixes);
+    if incompatible.is_empty() {
+        log::info!("Storage layout of copied items is compatible with the chain spec runtime");
+        return Ok(());
+    }
+    for item in &incompatible {
+        log::error!("Incompatible storage item {}", item);
+    }
+
+    Err(format!(
+        "Found {} incompatible storage items, remove --check-compat to merge anyway",
+        incompatible.len()
+    ))
+}
+
+/// Print the size of the merged chain spec, and warn if zombienet may fail to modify it.
+pub fn check_output_size(output_path: &str) -> io::Result<()> {
+    let final_size = fs::metadata(output_path)?.len();
+    log::info!("Final file size: {} bytes", final_size);
+    if final_size < 2 * 1024 * 1024 * 1024 {
+        log::warn!(
+            "Output file size is less than 2GB, zombienet will attempt to modify it and that may fail"
+        );
+        log::warn!(
+            "Use pad-with-spaces subcommand to workaround that. Note that this may not be needed, so try without it first."
+        );
+    }
+
+    Ok(())
 }
 
 pub async fn merge_into_raw<Block, HostFns>(
@@ -67,9 +143,9 @@ where
     //let pallet_prefix = hex::decode("359e684ff9b0738b7dc97123fd114c24").unwrap();
     let keep_prefixes = command
         .prefix
-        .into_iter()
+        .iter()
         .map(|x| {
-            hex::decode(&x).unwrap_or_else(|_e| {
+            hex::decode(x).unwrap_or_else(|_e| {
                 panic!(
                     "Failed to parse prefix key, should be in hex format (without leading 0x): {}",
                     x
@@ -79,7 +155,7 @@ where
         .chain(
             command
                 .pallet
-                .into_iter()
+                .iter()
                 .map(|pallet_name| twox_128(pallet_name.as_bytes()).to_vec()),
         )
         .collect::<Vec<_>>();
@@ -99,118 +175,46 @@ where
         panic!("Add at least one --pallet arg, or pass --all flag");
     }
 
-    // Convert each prefix (Vec<u8>) to a hex string.
-    let prefix_hexes: Vec<String> = keep_prefixes.iter().map(|p| hex::encode(p)).collect();
-
-    // Build a regex pattern that matches a line whose key (inside quotes) starts with "0x" and then one of the prefixes,
-    // followed by any hexadecimal digits, then a closing quote, optional whitespace, and a colon.
-    // For example:    "0x359e684f...":
-    let deletion_pattern = format!(r#"^\s*"0x(?:{})[0-9a-fA-F]*"\s*:"#, prefix_hexes.join("|"));
-    let deletion_regex = Regex::new(&deletion_pattern).expect("Invalid deletion regex");
+    let patch_source = open_source::<Block>(&command.hex_snapshot_path, &command.rpc).await?;
+    // An empty list of prefixes means all the keys
+    let patch_prefixes = if command.all {
+        vec![]
+    } else {
+        keep_prefixes.clone()
+    };
+    if command.check_compat {
+        check_compat::<HostFns>(&shared, &command, patch_source.as_ref(), &patch_prefixes)?;
+    }
 
     // If output path is none, overwrite input file as the last step
     let output_path = command
         .output_path
         .unwrap_or_else(|| command.chain_spec_path.clone());
-    // input_path: command.chain_spec_path
-    // patch_path: command.hex_snapshot_path
-
-    // This needs to open input file, copy to output
-    // Remove all storage keys that match prefixes
-    // If --all flag is set, remove all storage keys
-    // Then paste the snapshot keys into the raw spec, without loading into memory, just plain cat or whatever
-    // And finally move the output to input if the file is the same
-
-    // The insertion pattern: for example, a line like:    "top": {
-    let insertion_pattern = r#""top":\s*\{"#;
-    let top_regex = Regex::new(insertion_pattern).expect("Invalid insertion pattern regex");
-
-    let mut temp = NamedTempFile::new_in(Path::new(&command.chain_spec_path).parent().unwrap())?;
-    let input = File::open(command.chain_spec_path).inspect_err(|e| {
-        log::error!("Failed to open chain spec file: {}", e);
-    })?;
-    let reader = BufReader::new(input);
-    let patch_input = File::open(command.hex_snapshot_path).inspect_err(|e| {
-        log::error!("Failed to open hex snapshot file: {}", e);
-    })?;
-    let mut patch_reader = Some(BufReader::new(patch_input));
+
+    // Keys under the prefixes are removed from the chain spec and copied from the snapshot
+    let copied = |key: &[u8]| command.all || keep_prefixes.iter().any(|p| key.starts_with(p));
     let mut count_removed_keys = 0u64;
-    let mut count_inserted_keys = 0u64;
-    let mut count_skipped_from_snap = 0u64;
-    {
-        let mut writer = BufWriter::new(&mut temp);
-        // This is a weird state machine that depends on which line we are processing currently
-        let mut inserted = false;
-        let mut inside_top = false;
-        let mut trailing_comma_edge_case = false;
-        for line in reader.lines() {
-            let line = line?;
-            if inside_top && line.contains("}") {
-                // End of top object
-                inside_top = false;
-                if trailing_comma_edge_case {
-                    log::warn!("Need to manually remove trailing comma from top object");
-                }
-            }
-            if inside_top && (command.all || deletion_regex.is_match(&line)) {
-                // Skip this line (i.e. delete it)
+    let patch = patch_source.key_values_with_prefixes(patch_prefixes);
+    let stats = merge_key_values(
+        &command.chain_spec_path,
+        &output_path,
+        |key| {
+            let removed = copied(key);
+            if removed {
                 count_removed_keys += 1;
-                continue;
-            }
-            // Copy line to output
-            writeln!(writer, "{}", line)?;
-            trailing_comma_edge_case = false;
-
-            // Find start of "top" object
-            if !inserted && top_regex.is_match(&line) {
-                inside_top = true;
-                // Stream the patch file line by line and write to output.
-                let patch_reader = patch_reader.take().unwrap();
-                for patch_line in patch_reader.lines() {
-                    let patch_line = patch_line?;
-                    if command.all || deletion_regex.is_match(&patch_line) {
-                        writeln!(writer, "{}", patch_line)?;
-                        count_inserted_keys += 1;
-                    } else {
-                        count_skipped_from_snap += 1;
-                    }
-                }
-                inserted = true;
-                // TODO: Edge case, if there are no more keys in top object, we need to remove trailing comma
-                // Detect that but do not fix it
-                trailing_comma_edge_case = true;
             }
-        }
-        writer.flush()?;
-    }
+            removed
+        },
+        patch,
+    )?;
 
     log::info!(
         "Removed {} keys from existing chain spec",
         count_removed_keys
     );
-    log::info!("Inserted {} new keys from snapshot", count_inserted_keys);
-    if count_skipped_from_snap > 0 {
-        log::info!(
-            "{} keys not inserted from snapshot based on pallet prefix",
-            count_skipped_from_snap
-        );
-    }
-
-    temp.persist(&output_path)
-        .expect("Failed to persist output file");
+    log::info!("Inserted {} new keys from snapshot", stats.inserted);
 
-    // Now, print the size in bytes of the final file.
-    let metadata = fs::metadata(output_path)?;
-    let final_size = metadata.len();
-    log::info!("Final file size: {} bytes", final_size);
-    if final_size < 2 * 1024 * 1024 * 1024 {
-        log::warn!(
-            "Output file size is less than 2GB, zombienet will attempt to modify it and that may fail"
-        );
-        log::warn!(
-            "Use pad-with-spaces subcommand to workaround that. Note that this may not be needed, so try without it first."
-        );
-    }
+    check_output_size(&output_path)?;
 
     Ok(())
 }
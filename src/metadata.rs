@@ -0,0 +1,426 @@
+//! Read the runtime metadata from the wasm code and use it to name and build storage keys.
+
+use crate::scale_json;
+use crate::should_be_public::{build_executor, state_machine_call};
+use frame_metadata::v14::{
+    PalletStorageMetadata, StorageEntryMetadata, StorageEntryType, StorageHasher,
+};
+use frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed};
+use parity_scale_codec::Decode;
+use sc_executor::HostFunctions;
+use scale_info::form::PortableForm;
+use scale_info::{PortableRegistry, TypeDef};
+use sp_runtime::app_crypto::sp_core::{blake2_128, blake2_256, twox_128, twox_256, twox_64};
+use sp_runtime::traits::BlakeTwo256;
+use sp_state_machine::TestExternalities;
+use sp_version::RuntimeVersion;
+use std::collections::HashMap;
+use std::fs;
+use try_runtime_core::common::shared_parameters::{Runtime, SharedParams};
+
+/// Well known key of the runtime wasm code.
+pub const CODE_KEY: &[u8] = b":code";
+
+/// Metadata of a single pallet, only the parts we use.
+#[derive(Debug, Clone)]
+pub struct PalletInfo {
+    pub name: String,
+    pub index: u8,
+    pub storage: Option<PalletStorageMetadata<PortableForm>>,
+    /// Type id of the call enum of this pallet
+    pub calls: Option<u32>,
+    /// Encoded value of each constant, by name
+    pub constants: HashMap<String, Vec<u8>>,
+}
+
+/// Runtime metadata converted into a version independent format.
+#[derive(Debug, Clone)]
+pub struct RuntimeInfo {
+    pub types: PortableRegistry,
+    pub pallets: Vec<PalletInfo>,
+    /// `twox_128(pallet) ++ twox_128(item)` to (pallet index, entry index) in `pallets`
+    item_prefixes: HashMap<Vec<u8>, (usize, usize)>,
+}
+
+/// A storage item declared in the metadata.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageItem<'a> {
+    pub pallet: &'a str,
+    /// Storage prefix of the pallet, usually the same as the pallet name
+    pub prefix: &'a str,
+    pub entry: &'a StorageEntryMetadata<PortableForm>,
+}
+
+impl RuntimeInfo {
+    pub fn from_metadata(metadata: RuntimeMetadataPrefixed) -> Result<Self, String> {
+        let (types, pallets) = match metadata.1 {
+            RuntimeMetadata::V14(m) => (
+                m.types,
+                m.pallets
+                    .into_iter()
+                    .map(|p| PalletInfo {
+                        name: p.name,
+                        index: p.index,
+                        storage: p.storage,
+                        calls: p.calls.map(|c| c.ty.id),
+                        constants: p.constants.into_iter().map(|c| (c.name, c.value)).collect(),
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            RuntimeMetadata::V15(m) => (
+                m.types,
+                m.pallets
+                    .into_iter()
+                    .map(|p| PalletInfo {
+                        name: p.name,
+                        index: p.index,
+                        storage: p.storage,
+                        calls: p.calls.map(|c| c.ty.id),
+                        constants: p.constants.into_iter().map(|c| (c.name, c.value)).collect(),
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            _ => {
+                return Err(format!(
+                    "Unsupported metadata version: {}",
+                    metadata.1.version()
+                ))
+            }
+        };
+
+        let mut item_prefixes = HashMap::new();
+        for (pallet_idx, pallet) in pallets.iter().enumerate() {
+            if let Some(storage) = &pallet.storage {
+                for (entry_idx, entry) in storage.entries.iter().enumerate() {
+                    item_prefixes.insert(
+                        storage_prefix(&storage.prefix, &entry.name),
+                        (pallet_idx, entry_idx),
+                    );
+                }
+            }
+        }
+
+        Ok(Self {
+            types,
+            pallets,
+            item_prefixes,
+        })
+    }
+
+    pub fn pallet(&self, name: &str) -> Option<&PalletInfo> {
+        self.pallets.iter().find(|p| p.name == name)
+    }
+
+    /// Find a storage item by pallet name and item name.
+    pub fn storage_item(&self, pallet: &str, item: &str) -> Result<StorageItem, String> {
+        let pallet_info = self
+            .pallet(pallet)
+            .ok_or_else(|| format!("Pallet {} not found in metadata", pallet))?;
+        let storage = pallet_info
+            .storage
+            .as_ref()
+            .ok_or_else(|| format!("Pallet {} has no storage", pallet))?;
+        let entry = storage
+            .entries
+            .iter()
+            .find(|e| e.name == item)
+            .ok_or_else(|| format!("Storage item {}::{} not found in metadata", pallet, item))?;
+
+        Ok(StorageItem {
+            pallet: &pallet_info.name,
+            prefix: &storage.prefix,
+            entry,
+        })
+    }
+
+    /// Decode a constant of a pallet, such as `Scheduler::MaxScheduledPerBlock`.
+    pub fn constant<T: Decode>(&self, pallet: &str, name: &str) -> Result<T, String> {
+        let value = self
+            .pallet(pallet)
+            .ok_or_else(|| format!("Pallet {} not found in metadata", pallet))?
+            .constants
+            .get(name)
+            .ok_or_else(|| format!("Constant {}::{} not found in metadata", pallet, name))?;
+        T::decode(&mut &value[..])
+            .map_err(|e| format!("Failed to decode constant {}::{}: {}", pallet, name, e))
+    }
+
+    /// Find the storage item that owns this key, using the first 32 bytes of the key.
+    pub fn storage_item_of_key(&self, key: &[u8]) -> Option<StorageItem> {
+        let (pallet_idx, entry_idx) = self.item_prefixes.get(key.get(..32)?)?;
+        let pallet = &self.pallets[*pallet_idx];
+        let storage = pallet.storage.as_ref()?;
+
+        Some(StorageItem {
+            pallet: &pallet.name,
+            prefix: &storage.prefix,
+            entry: &storage.entries[*entry_idx],
+        })
+    }
+
+    /// Iterate over all the storage items of all the pallets.
+    pub fn storage_items(&self) -> impl Iterator<Item = StorageItem> + '_ {
+        self.pallets.iter().flat_map(|pallet| {
+            pallet.storage.iter().flat_map(move |storage| {
+                storage.entries.iter().map(move |entry| StorageItem {
+                    pallet: &pallet.name,
+                    prefix: &storage.prefix,
+                    entry,
+                })
+            })
+        })
+    }
+}
+
+impl StorageItem<'_> {
+    /// `twox_128(pallet) ++ twox_128(item)`
+    pub fn prefix(&self) -> Vec<u8> {
+        storage_prefix(self.prefix, &self.entry.name)
+    }
+
+    /// Hashers of the map keys, empty for storage values.
+    pub fn hashers(&self) -> &[StorageHasher] {
+        match &self.entry.ty {
+            StorageEntryType::Plain(_) => &[],
+            StorageEntryType::Map { hashers, .. } => hashers,
+        }
+    }
+
+    pub fn value_type(&self) -> u32 {
+        match &self.entry.ty {
+            StorageEntryType::Plain(ty) => ty.id,
+            StorageEntryType::Map { value, .. } => value.id,
+        }
+    }
+
+    /// Type of each of the map keys, one per hasher.
+    pub fn key_types(&self, types: &PortableRegistry) -> Result<Vec<u32>, String> {
+        match &self.entry.ty {
+            StorageEntryType::Plain(_) => Ok(vec![]),
+            StorageEntryType::Map { hashers, key, .. } => {
+                if hashers.len() == 1 {
+                    return Ok(vec![key.id]);
+                }
+                let key_ty = types
+                    .resolve(key.id)
+                    .ok_or_else(|| format!("Type {} not found in metadata", key.id))?;
+                match &key_ty.type_def {
+                    TypeDef::Tuple(tuple) if tuple.fields.len() == hashers.len() => {
+                        Ok(tuple.fields.iter().map(|f| f.id).collect())
+                    }
+                    _ => Err(format!(
+                        "Storage item {} has {} hashers but its key is not a tuple of that length",
+                        self,
+                        hashers.len()
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Build the storage key from map keys given as JSON, see [`Self::key`].
+    pub fn key_from_json(
+        &self,
+        types: &PortableRegistry,
+        keys: &[serde_json::Value],
+    ) -> Result<Vec<u8>, String> {
+        let encoded_keys = self
+            .key_types(types)?
+            .into_iter()
+            .zip(keys)
+            .map(|(ty, key)| scale_json::encode(types, ty, key))
+            .collect::<Result<Vec<_>, _>>()?;
+        if encoded_keys.len() < keys.len() {
+            return Err(format!(
+                "Storage item {} takes {} keys, got {}",
+                self,
+                encoded_keys.len(),
+                keys.len()
+            ));
+        }
+
+        self.key(&encoded_keys)
+    }
+
+    /// Build the full storage key from the already SCALE-encoded map keys. Passing less keys
+    /// than hashers returns a prefix of the map.
+    pub fn key(&self, encoded_keys: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+        let hashers = self.hashers();
+        if encoded_keys.len() > hashers.len() {
+            return Err(format!(
+                "Storage item {} takes {} keys, got {}",
+                self,
+                hashers.len(),
+                encoded_keys.len()
+            ));
+        }
+
+        let mut key = self.prefix();
+        for (hasher, encoded) in hashers.iter().zip(encoded_keys) {
+            key.extend(hash_key(hasher, encoded));
+        }
+
+        Ok(key)
+    }
+
+    /// Decode the map keys from a full storage key. Keys with opaque hashers cannot be decoded,
+    /// for those the hash is returned as a hex string.
+    pub fn decode_key(
+        &self,
+        types: &PortableRegistry,
+        key: &[u8],
+    ) -> Result<Vec<serde_json::Value>, String> {
+        let mut input = key
+            .get(32..)
+            .ok_or_else(|| format!("Key of {} too short", self))?;
+        let mut keys = vec![];
+
+        for (hasher, ty) in self.hashers().iter().zip(self.key_types(types)?) {
+            let len = hash_len(hasher);
+            if input.len() < len {
+                return Err(format!("Key of {} too short", self));
+            }
+            let (hash, rest) = input.split_at(len);
+            input = rest;
+            if is_transparent(hasher) {
+                keys.push(scale_json::decode(types, ty, &mut input)?);
+            } else {
+                keys.push(serde_json::Value::String(format!(
+                    "0x{}",
+                    hex::encode(hash)
+                )));
+            }
+        }
+        if !input.is_empty() {
+            return Err(format!(
+                "{} bytes left after decoding key of {}",
+                input.len(),
+                self
+            ));
+        }
+
+        Ok(keys)
+    }
+}
+
+impl std::fmt::Display for StorageItem<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}::{}", self.pallet, self.entry.name)
+    }
+}
+
+pub fn storage_prefix(pallet: &str, item: &str) -> Vec<u8> {
+    [twox_128(pallet.as_bytes()), twox_128(item.as_bytes())].concat()
+}
+
+pub fn hash_key(hasher: &StorageHasher, encoded: &[u8]) -> Vec<u8> {
+    match hasher {
+        StorageHasher::Blake2_128 => blake2_128(encoded).to_vec(),
+        StorageHasher::Blake2_256 => blake2_256(encoded).to_vec(),
+        StorageHasher::Blake2_128Concat => [&blake2_128(encoded)[..], encoded].concat(),
+        StorageHasher::Twox128 => twox_128(encoded).to_vec(),
+        StorageHasher::Twox256 => twox_256(encoded).to_vec(),
+        StorageHasher::Twox64Concat => [&twox_64(encoded)[..], encoded].concat(),
+        StorageHasher::Identity => encoded.to_vec(),
+    }
+}
+
+/// Length of the hash that the hasher puts before the key. For the concat hashers the encoded
+/// key follows the hash.
+pub fn hash_len(hasher: &StorageHasher) -> usize {
+    match hasher {
+        StorageHasher::Blake2_128 | StorageHasher::Twox128 | StorageHasher::Blake2_128Concat => 16,
+        StorageHasher::Blake2_256 | StorageHasher::Twox256 => 32,
+        StorageHasher::Twox64Concat => 8,
+        StorageHasher::Identity => 0,
+    }
+}
+
+/// True if the original key can be read back from the hashed key.
+pub fn is_transparent(hasher: &StorageHasher) -> bool {
+    matches!(
+        hasher,
+        StorageHasher::Blake2_128Concat | StorageHasher::Twox64Concat | StorageHasher::Identity
+    )
+}
+
+/// Parse a `Pallet::Item` string.
+pub fn parse_item_path(path: &str) -> Result<(String, String), String> {
+    match path.split_once("::") {
+        Some((pallet, item)) if !pallet.is_empty() && !item.is_empty() => {
+            Ok((pallet.to_string(), item.to_string()))
+        }
+        _ => Err(format!(
+            "Expected storage item as Pallet::Item, found: {}",
+            path
+        )),
+    }
+}
+
+/// Get the runtime code to use. If `--runtime` was passed as a path, read it from there,
+/// otherwise use the `:code` found in the state.
+pub fn runtime_code(
+    shared: &SharedParams,
+    state_code: impl FnOnce() -> std::io::Result<Option<Vec<u8>>>,
+) -> Result<Vec<u8>, String> {
+    match &shared.runtime {
+        Runtime::Path(path) => fs::read(path)
+            .map_err(|e| format!("Failed to read runtime from {}: {}", path.display(), e)),
+        Runtime::Existing => state_code()
+            .map_err(|e| format!("Failed to read :code from state: {}", e))?
+            .ok_or_else(|| {
+                "State does not contain :code, pass the runtime wasm with --runtime".to_string()
+            }),
+    }
+}
+
+/// Execute a runtime api `method` on this runtime code, with an otherwise empty state.
+pub fn call_runtime_api<HostFns: HostFunctions>(
+    shared: &SharedParams,
+    code: &[u8],
+    method: &'static str,
+    data: &[u8],
+) -> Result<Vec<u8>, String> {
+    let executor = build_executor::<HostFns>(shared);
+    let ext = TestExternalities::<BlakeTwo256>::new_with_code(code, Default::default());
+    let (_changes, encoded) = state_machine_call(&ext, &executor, method, data, Default::default())
+        .map_err(|e| e.to_string())?;
+
+    Ok(encoded)
+}
+
+/// Execute `Metadata_metadata` on this runtime code.
+pub fn fetch_metadata<HostFns: HostFunctions>(
+    shared: &SharedParams,
+    code: &[u8],
+) -> Result<RuntimeMetadataPrefixed, String> {
+    let encoded = call_runtime_api::<HostFns>(shared, code, "Metadata_metadata", &[])?;
+
+    // The runtime api returns `OpaqueMetadata`, which is a `Vec<u8>`
+    let bytes = Vec::<u8>::decode(&mut &encoded[..])
+        .map_err(|e| format!("Failed to decode opaque metadata: {}", e))?;
+    RuntimeMetadataPrefixed::decode(&mut &bytes[..])
+        .map_err(|e| format!("Failed to decode metadata: {}", e))
+}
+
+/// Execute `Core_version` on this runtime code.
+pub fn fetch_runtime_version<HostFns: HostFunctions>(
+    shared: &SharedParams,
+    code: &[u8],
+) -> Result<RuntimeVersion, String> {
+    let encoded = call_runtime_api::<HostFns>(shared, code, "Core_version", &[])?;
+
+    RuntimeVersion::decode(&mut &encoded[..])
+        .map_err(|e| format!("Failed to decode runtime version: {}", e))
+}
+
+pub fn load_runtime_info<HostFns: HostFunctions>(
+    shared: &SharedParams,
+    code: &[u8],
+) -> Result<RuntimeInfo, String> {
+    let metadata = fetch_metadata::<HostFns>(shared, code)?;
+    let info = RuntimeInfo::from_metadata(metadata)?;
+    log::info!("Loaded metadata with {} pallets", info.pallets.len());
+
+    Ok(info)
+}
@@ -0,0 +1,144 @@
+use crate::metadata::{load_runtime_info, runtime_code, RuntimeInfo};
+use crate::state_file::format_kv;
+use crate::state_source::{open_source, RpcConfig};
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use sp_runtime::app_crypto::sp_core::twox_128;
+use sp_runtime::traits::NumberFor;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Key suffix used by `frame_support` to store the storage version of each pallet. It is not
+/// declared in the metadata.
+const STORAGE_VERSION_KEY: &[u8] = b":__STORAGE_VERSION__:";
+
+/// Configurations for [`orphans`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct OrphansCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to read. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Write a hex snapshot without the orphaned keys to this path.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+#[derive(Debug, Default)]
+struct OrphanPrefix {
+    keys: u64,
+    bytes: u64,
+}
+
+/// Returns the name of the orphaned prefix that owns this key, or `None` if the key belongs to a
+/// storage item of the runtime.
+fn orphan_prefix(
+    runtime: &RuntimeInfo,
+    pallet_names: &HashMap<Vec<u8>, String>,
+    key: &[u8],
+) -> Option<String> {
+    if key.starts_with(b":") || runtime.storage_item_of_key(key).is_some() {
+        return None;
+    }
+    let Some(pallet) = key.get(..16).and_then(|p| pallet_names.get(p)) else {
+        // Unknown pallet
+        return Some(format!("0x{}", hex::encode(&key[..key.len().min(16)])));
+    };
+    match key.get(16..32) {
+        Some(item) if item == twox_128(STORAGE_VERSION_KEY) => None,
+        Some(item) => Some(format!("{}::0x{}", pallet, hex::encode(item))),
+        None => Some(format!("0x{}", hex::encode(key))),
+    }
+}
+
+pub async fn orphans<Block, HostFns>(
+    shared: SharedParams,
+    command: OrphansCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let input = open_source::<Block>(&command.state_path, &command.rpc).await?;
+    let code = runtime_code(&shared, || input.code())?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    // Pallets without storage items still have a storage version, so include all of them
+    let pallet_names: HashMap<Vec<u8>, String> = runtime
+        .pallets
+        .iter()
+        .map(|p| (twox_128(p.name.as_bytes()).to_vec(), p.name.clone()))
+        .chain(
+            runtime
+                .pallets
+                .iter()
+                .filter_map(|p| p.storage.as_ref())
+                .map(|s| (twox_128(s.prefix.as_bytes()).to_vec(), s.prefix.clone())),
+        )
+        .collect();
+
+    let mut output = match &command.output_path {
+        Some(output_path) => Some(BufWriter::new(File::create(output_path).inspect_err(
+            |e| {
+                log::error!("Failed to create output file: {}", e);
+            },
+        )?)),
+        None => None,
+    };
+
+    let mut orphans: BTreeMap<String, OrphanPrefix> = BTreeMap::new();
+    let mut count_kept = 0u64;
+    for kv in input.key_values()? {
+        let (key, value) = kv?;
+        let kv = format_kv(&key, &value);
+        match orphan_prefix(&runtime, &pallet_names, &key) {
+            Some(prefix) => {
+                let orphan = orphans.entry(prefix).or_default();
+                orphan.keys += 1;
+                // Add 2 bytes for the comma and newline
+                orphan.bytes += kv.len() as u64 + 2;
+            }
+            None => {
+                count_kept += 1;
+                if let Some(output) = &mut output {
+                    writeln!(output, "{},", kv)?;
+                }
+            }
+        }
+    }
+    if let Some(output) = &mut output {
+        output.flush()?;
+    }
+
+    let mut report = orphans.into_iter().collect::<Vec<_>>();
+    report.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes));
+    for (prefix, orphan) in &report {
+        log::info!(
+            "{}: {} orphaned keys, {} bytes",
+            prefix,
+            orphan.keys,
+            orphan.bytes
+        );
+    }
+    log::info!(
+        "Found {} orphaned keys, {} bytes. {} keys belong to the runtime",
+        report.iter().map(|(_, orphan)| orphan.keys).sum::<u64>(),
+        report.iter().map(|(_, orphan)| orphan.bytes).sum::<u64>(),
+        count_kept
+    );
+
+    Ok(())
+}
@@ -0,0 +1,412 @@
+use crate::metadata::{parse_item_path, storage_prefix};
+use crate::parse;
+use crate::state_file::format_kv;
+use crate::state_source::{open_source_with_block, rewrite_state, RpcConfig, StateSource};
+use crate::BlockT;
+use parity_scale_codec::{Decode, Encode};
+use sc_executor::HostFunctions;
+use sp_runtime::traits::NumberFor;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Debug;
+use std::io;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Groups of storage items that are usually not needed for testing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PruneProfile {
+    /// Events of the last block
+    Events,
+    /// Hashes of old blocks
+    BlockHash,
+    /// Session history kept for slashing reports
+    Historical,
+    /// Preimages and their status
+    Preimage,
+    /// Queued XCM and UMP/DMP messages
+    MessageQueues,
+    /// All of the above
+    All,
+}
+
+impl PruneProfile {
+    fn items(&self) -> Vec<(&'static str, &'static str)> {
+        match self {
+            PruneProfile::Events => vec![
+                ("System", "Events"),
+                ("System", "EventCount"),
+                ("System", "EventTopics"),
+            ],
+            PruneProfile::BlockHash => vec![("System", "BlockHash")],
+            PruneProfile::Historical => vec![
+                ("Historical", "HistoricalSessions"),
+                ("Historical", "StoredRange"),
+            ],
+            PruneProfile::Preimage => vec![
+                ("Preimage", "PreimageFor"),
+                ("Preimage", "StatusFor"),
+                ("Preimage", "RequestStatusFor"),
+            ],
+            PruneProfile::MessageQueues => vec![
+                ("MessageQueue", "BookStateFor"),
+                ("MessageQueue", "ServiceHead"),
+                ("MessageQueue", "Pages"),
+                ("XcmpQueue", "InboundXcmpSuspended"),
+                ("XcmpQueue", "OutboundXcmpStatus"),
+                ("XcmpQueue", "OutboundXcmpMessages"),
+                ("XcmpQueue", "SignalMessages"),
+                ("ParachainSystem", "PendingUpwardMessages"),
+                ("ParachainSystem", "UpwardMessages"),
+            ],
+            PruneProfile::All => [
+                PruneProfile::Events,
+                PruneProfile::BlockHash,
+                PruneProfile::Historical,
+                PruneProfile::Preimage,
+                PruneProfile::MessageQueues,
+            ]
+            .iter()
+            .flat_map(|p| p.items())
+            .collect(),
+        }
+    }
+}
+
+/// Keys under the pruned storage items that the chain still needs:
+///
+/// * `System::BlockHash(0)`, the genesis hash
+/// * `Historical::HistoricalSessions` from the current session on, and `Historical::StoredRange`
+///   which is shortened to start at the current session
+/// * Preimages of calls scheduled in `Scheduler::Agenda`, which `Scheduler::Lookup` points to
+pub struct InUse {
+    block_hash: Vec<u8>,
+    historical_sessions: Vec<u8>,
+    stored_range: Vec<u8>,
+    preimages: Vec<Vec<u8>>,
+    current_session: u32,
+    /// Hashes of the preimages found in a scheduled call
+    scheduled_preimages: HashSet<Vec<u8>>,
+}
+
+impl InUse {
+    /// Read the current session and the scheduled preimages of the state.
+    pub fn load(source: &dyn StateSource) -> io::Result<Self> {
+        let current_index = storage_prefix("Session", "CurrentIndex");
+        let agenda = storage_prefix("Scheduler", "Agenda");
+        let statuses = [
+            storage_prefix("Preimage", "StatusFor"),
+            storage_prefix("Preimage", "RequestStatusFor"),
+        ];
+
+        let mut current_session = 0;
+        let mut agendas = vec![];
+        let mut hashes = HashSet::new();
+        let prefixes = [
+            vec![current_index.clone(), agenda.clone()],
+            statuses.to_vec(),
+        ]
+        .concat();
+        for kv in source.key_values_with_prefixes(prefixes) {
+            let (key, value) = kv?;
+            if key == current_index {
+                current_session = u32::decode(&mut &value[..])
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            } else if key.starts_with(&agenda) {
+                agendas.push(value);
+            } else if let Some(status) = statuses.iter().find(|p| key.starts_with(p)) {
+                // The key is the identity hashed preimage hash
+                hashes.insert(key[status.len()..].to_vec());
+            }
+        }
+
+        // Scheduled calls that are too big to be inlined store the preimage hash, so a preimage is
+        // in use if its hash appears in an agenda
+        let scheduled_preimages: HashSet<Vec<u8>> = hashes
+            .into_iter()
+            .filter(|hash| {
+                agendas
+                    .iter()
+                    .any(|agenda| agenda.windows(hash.len()).any(|window| window == &hash[..]))
+            })
+            .collect();
+        if !scheduled_preimages.is_empty() {
+            log::info!(
+                "Keeping {} preimages of scheduled calls",
+                scheduled_preimages.len()
+            );
+        }
+
+        Ok(Self {
+            block_hash: storage_prefix("System", "BlockHash"),
+            historical_sessions: storage_prefix("Historical", "HistoricalSessions"),
+            stored_range: storage_prefix("Historical", "StoredRange"),
+            preimages: vec![
+                storage_prefix("Preimage", "PreimageFor"),
+                statuses[0].clone(),
+                statuses[1].clone(),
+            ],
+            current_session,
+            scheduled_preimages,
+        })
+    }
+
+    /// Value to keep for a key under a pruned prefix, or `None` if the key can be removed.
+    pub fn keep(&self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        if let Some(number) = key.strip_prefix(&self.block_hash[..]) {
+            // Twox64Concat hashed block number
+            let number = number.get(8..)?;
+            return number.iter().all(|b| *b == 0).then(|| value.to_vec());
+        }
+        if let Some(index) = key.strip_prefix(&self.historical_sessions[..]) {
+            let index = u32::decode(&mut index.get(8..)?).ok()?;
+            return (index >= self.current_session).then(|| value.to_vec());
+        }
+        if key == self.stored_range {
+            let (start, end) = <(u32, u32)>::decode(&mut &value[..]).ok()?;
+            return Some((start.max(self.current_session), end).encode());
+        }
+        let preimage = self.preimages.iter().find(|p| key.starts_with(p))?;
+        let hash = &key[preimage.len()..];
+        self.scheduled_preimages
+            .iter()
+            .any(|scheduled| hash.starts_with(scheduled))
+            .then(|| value.to_vec())
+    }
+}
+
+/// Configurations for [`prune`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct PruneCommand {
+    /// Built-in list of storage items to remove. Can be provided multiple times.
+    #[arg(long, value_enum, num_args = 1..)]
+    pub profile: Vec<PruneProfile>,
+
+    /// Storage item to remove, as `Pallet::Item`. Can be provided multiple times.
+    #[arg(long, num_args = 1..)]
+    pub item: Vec<String>,
+
+    /// Storage key prefixes to remove. Pass as 0x prefixed hex strings.
+    #[arg(long, value_parser = parse::hash, num_args = 1..)]
+    pub prefix: Vec<String>,
+
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to prune. Snapshots must
+    /// end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Output path, defaults to input path. A snapshot or a node can also be written as a
+    /// snapshot of its block if the path ends in `.snap`, otherwise as a hex snapshot.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    /// Only print how many bytes would be saved, do not write any file.
+    #[clap(long)]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+#[derive(Debug, Default)]
+struct PrunedItem {
+    keys: u64,
+    bytes: u64,
+}
+
+/// Name of each storage item to remove, used in the report, and its prefix.
+pub fn prune_prefixes(
+    profiles: &[PruneProfile],
+    items: &[String],
+    prefixes_hex: &[String],
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut prefixes: Vec<(String, Vec<u8>)> = vec![];
+    for profile in profiles {
+        for (pallet, item) in profile.items() {
+            prefixes.push((
+                format!("{}::{}", pallet, item),
+                storage_prefix(pallet, item),
+            ));
+        }
+    }
+    for path in items {
+        let (pallet, item) = parse_item_path(path)?;
+        prefixes.push((path.clone(), storage_prefix(&pallet, &item)));
+    }
+    for prefix in prefixes_hex {
+        let prefix_bytes = hex::decode(prefix).unwrap_or_else(|_e| {
+            panic!(
+                "Failed to parse prefix key, should be in hex format (without leading 0x): {}",
+                prefix
+            )
+        });
+        prefixes.push((format!("0x{}", prefix), prefix_bytes));
+    }
+
+    Ok(prefixes)
+}
+
+pub async fn prune<Block, HostFns>(
+    _shared: SharedParams,
+    command: PruneCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let prefixes = prune_prefixes(&command.profile, &command.item, &command.prefix)?;
+
+    if prefixes.is_empty() {
+        panic!("Add at least one --profile, --item or --prefix arg");
+    }
+
+    let (source, block) =
+        open_source_with_block::<Block>(&command.state_path, &command.rpc).await?;
+    let in_use = InUse::load(source.as_ref())?;
+    let mut kept = 0u64;
+    let mut pruned: BTreeMap<String, PrunedItem> = BTreeMap::new();
+    let mut prune_key = |key: Vec<u8>, value: Vec<u8>| -> Option<(Vec<u8>, Vec<u8>)> {
+        let Some((name, _)) = prefixes.iter().find(|(_, p)| key.starts_with(p)) else {
+            return Some((key, value));
+        };
+        if let Some(value) = in_use.keep(&key, &value) {
+            kept += 1;
+            return Some((key, value));
+        }
+        let item = pruned.entry(name.clone()).or_default();
+        item.keys += 1;
+        // Line in the output file, plus comma and newline
+        item.bytes += format_kv(&key, &value).len() as u64 + 2;
+        None
+    };
+
+    if command.dry_run {
+        for kv in source.key_values()? {
+            let (key, value) = kv?;
+            prune_key(key, value);
+        }
+    } else {
+        let output_path = command
+            .output_path
+            .unwrap_or_else(|| command.state_path.clone());
+        rewrite_state::<Block, _>(
+            &command.state_path,
+            source.as_ref(),
+            block,
+            &output_path,
+            BTreeMap::new(),
+            prune_key,
+        )?;
+    }
+
+    if kept > 0 {
+        log::info!("Kept {} keys that are still in use", kept);
+    }
+    let mut report = pruned.into_iter().collect::<Vec<_>>();
+    report.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes));
+    for (name, item) in &report {
+        log::info!("{}: removed {} keys, {} bytes", name, item.keys, item.bytes);
+    }
+    log::info!(
+        "Total: removed {} keys, {} bytes",
+        report.iter().map(|(_, item)| item.keys).sum::<u64>(),
+        report.iter().map(|(_, item)| item.bytes).sum::<u64>()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state_source::MemorySource;
+    use sp_runtime::app_crypto::sp_core::twox_64;
+
+    fn twox64_concat(encoded: &[u8]) -> Vec<u8> {
+        [&twox_64(encoded)[..], encoded].concat()
+    }
+
+    fn in_use() -> InUse {
+        let hash = [7u8; 32];
+        let agenda = [Some((3u8, hash, 100u32))].encode();
+        let state = BTreeMap::from([
+            (storage_prefix("Session", "CurrentIndex"), 5u32.encode()),
+            (
+                [
+                    storage_prefix("Scheduler", "Agenda"),
+                    twox64_concat(&10u32.encode()),
+                ]
+                .concat(),
+                agenda,
+            ),
+            (
+                [&storage_prefix("Preimage", "StatusFor")[..], &hash[..]].concat(),
+                vec![],
+            ),
+            (
+                [&storage_prefix("Preimage", "StatusFor")[..], &[8u8; 32][..]].concat(),
+                vec![],
+            ),
+        ]);
+
+        InUse::load(&MemorySource(state)).unwrap()
+    }
+
+    #[test]
+    fn keeps_genesis_hash() {
+        let in_use = in_use();
+        let block_hash = |number: u32| {
+            [
+                storage_prefix("System", "BlockHash"),
+                twox64_concat(&number.encode()),
+            ]
+            .concat()
+        };
+
+        assert_eq!(in_use.keep(&block_hash(0), &[1]), Some(vec![1]));
+        assert_eq!(in_use.keep(&block_hash(1), &[1]), None);
+    }
+
+    #[test]
+    fn keeps_current_sessions() {
+        let in_use = in_use();
+        let session = |index: u32| {
+            [
+                storage_prefix("Historical", "HistoricalSessions"),
+                twox64_concat(&index.encode()),
+            ]
+            .concat()
+        };
+
+        assert_eq!(in_use.keep(&session(4), &[1]), None);
+        assert_eq!(in_use.keep(&session(5), &[1]), Some(vec![1]));
+        assert_eq!(in_use.keep(&session(6), &[1]), Some(vec![1]));
+        assert_eq!(
+            in_use.keep(
+                &storage_prefix("Historical", "StoredRange"),
+                &(0u32, 7u32).encode()
+            ),
+            Some((5u32, 7u32).encode())
+        );
+    }
+
+    #[test]
+    fn keeps_scheduled_preimages() {
+        let in_use = in_use();
+        let preimage = |hash: [u8; 32]| {
+            [
+                &storage_prefix("Preimage", "PreimageFor")[..],
+                &hash[..],
+                &100u32.encode()[..],
+            ]
+            .concat()
+        };
+
+        assert_eq!(in_use.keep(&preimage([7; 32]), &[1]), Some(vec![1]));
+        assert_eq!(in_use.keep(&preimage([8; 32]), &[1]), None);
+    }
+}
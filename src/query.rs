@@ -0,0 +1,101 @@
+use crate::decode::decode_entry;
+use crate::metadata::{load_runtime_info, parse_item_path, runtime_code};
+use crate::scale_json::{decode_all, parse_json_arg};
+use crate::state_source::{open_source, RpcConfig};
+use crate::BlockT;
+use frame_metadata::v14::StorageEntryModifier;
+use sc_executor::HostFunctions;
+use serde_json::Value;
+use sp_runtime::traits::NumberFor;
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`query`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct QueryCommand {
+    /// Storage item to read, as `Pallet::Item`.
+    pub item: String,
+
+    /// Map keys as JSON, one for each hasher of the storage item. Strings do not need quotes.
+    pub keys: Vec<String>,
+
+    /// List all the entries of the map that start with the given keys, instead of reading a
+    /// single value.
+    #[clap(long)]
+    pub iter: bool,
+
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to read. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+pub async fn query<Block, HostFns>(
+    shared: SharedParams,
+    command: QueryCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let input = open_source::<Block>(&command.state_path, &command.rpc).await?;
+    let code = runtime_code(&shared, || input.code())?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    let (pallet, item) = parse_item_path(&command.item)?;
+    let item = runtime.storage_item(&pallet, &item)?;
+    let keys = command
+        .keys
+        .iter()
+        .map(|k| parse_json_arg(k))
+        .collect::<Vec<_>>();
+    let key = item.key_from_json(&runtime.types, &keys)?;
+
+    if command.iter {
+        let mut count = 0u64;
+        for kv in input.key_values_with_prefix(&key)? {
+            let (key, value) = kv?;
+            println!("{}", decode_entry(&runtime, &key, &value));
+            count += 1;
+        }
+        log::info!("Found {} entries", count);
+        return Ok(());
+    }
+
+    if keys.len() != item.hashers().len() {
+        return Err(format!(
+            "Storage item {} takes {} keys, got {}. Use --iter to list all the entries",
+            item,
+            item.hashers().len(),
+            keys.len()
+        )
+        .into());
+    }
+
+    log::info!("Reading key 0x{}", hex::encode(&key));
+    let value = match input.get(&key)? {
+        Some(value) => decode_all(&runtime.types, item.value_type(), &value)?,
+        None => match item.entry.modifier {
+            StorageEntryModifier::Optional => {
+                log::info!("Key not found");
+                Value::Null
+            }
+            StorageEntryModifier::Default => {
+                log::info!("Key not found, showing default value");
+                decode_all(&runtime.types, item.value_type(), &item.entry.default)?
+            }
+        },
+    };
+    println!("{:#}", value);
+
+    Ok(())
+}
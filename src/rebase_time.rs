@@ -0,0 +1,365 @@
+use crate::metadata::{load_runtime_info, runtime_code, storage_prefix, RuntimeInfo};
+use crate::scale_json;
+use crate::state_source::{open_source_with_block, rewrite_state, RpcConfig};
+use crate::BlockT;
+use parity_scale_codec::{Compact, Decode, Encode};
+use sc_executor::HostFunctions;
+use scale_info::{PortableRegistry, TypeDef};
+use sp_runtime::app_crypto::sp_core::twox_64;
+use sp_runtime::traits::NumberFor;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`rebase_time`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct RebaseTimeCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to modify. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Output path, defaults to input path. A snapshot or a node can also be written as a
+    /// snapshot of its block if the path ends in `.snap`, otherwise as a hex snapshot.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    /// Timestamp of the genesis block in milliseconds, defaults to the current time
+    #[clap(long)]
+    pub genesis_timestamp: Option<u64>,
+
+    /// Slot duration in milliseconds, used to compute `Aura::CurrentSlot`
+    #[clap(long, default_value_t = 6000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub slot_duration: u64,
+
+    /// Also move the `Scheduler::Agenda` entries back by the old block number, so they run the
+    /// same number of blocks after genesis. Overdue tasks are moved to block 1, and to the next
+    /// blocks when there are more than `Scheduler::MaxScheduledPerBlock` of them.
+    #[clap(long)]
+    pub shift_scheduler: bool,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// The parent hash that `frame_system` uses for the genesis block.
+const HASH69: [u8; 32] = [0x69; 32];
+
+/// Key of a map with a single `Twox64Concat` key.
+fn twox64_concat_key(prefix: &[u8], key: &[u8]) -> Vec<u8> {
+    [prefix, &twox_64(key), key].concat()
+}
+
+/// Block number of a `Scheduler::Agenda` key, `None` if the key is malformed.
+fn agenda_block(agenda_prefix: &[u8], key: &[u8]) -> Option<u32> {
+    // Prefix, then twox_64 hash, then block number
+    let block = key.strip_prefix(agenda_prefix)?.get(8..)?;
+    Some(u32::from_le_bytes(block.try_into().ok()?))
+}
+
+/// Agendas moved to their new block number, and the new address of each task to update
+/// `Scheduler::Lookup`.
+struct ShiftedAgendas {
+    agendas: BTreeMap<u32, Vec<u8>>,
+    task_addresses: HashMap<(u32, u32), (u32, u32)>,
+}
+
+/// Type id of the tasks in a `Scheduler::Agenda`, which is a `BoundedVec` of tasks.
+fn agenda_task_type(runtime: &RuntimeInfo) -> Result<u32, String> {
+    let mut ty = runtime.storage_item("Scheduler", "Agenda")?.value_type();
+    loop {
+        let resolved = runtime
+            .types
+            .resolve(ty)
+            .ok_or_else(|| format!("Type {} not found in metadata", ty))?;
+        match &resolved.type_def {
+            // `BoundedVec` wraps a `Vec`
+            TypeDef::Composite(composite) if composite.fields.len() == 1 => {
+                ty = composite.fields[0].ty.id
+            }
+            TypeDef::Sequence(sequence) => return Ok(sequence.type_param.id),
+            _ => return Err("Scheduler::Agenda is not a vector of tasks".to_string()),
+        }
+    }
+}
+
+/// Move the agendas back by `offset` blocks. The tasks of overdue agendas are moved to block 1,
+/// and overflow into the next blocks so that no agenda has more than `max_per_block` tasks.
+fn shift_agendas(
+    types: &PortableRegistry,
+    task_type: u32,
+    old_agendas: BTreeMap<u32, Vec<u8>>,
+    offset: u32,
+    max_per_block: u32,
+) -> sc_cli::Result<ShiftedAgendas> {
+    if max_per_block == 0 {
+        return Err("Scheduler::MaxScheduledPerBlock is 0".into());
+    }
+    // New block number to (number of tasks, encoded tasks)
+    let mut merged: BTreeMap<u32, (u32, Vec<u8>)> = BTreeMap::new();
+    let mut task_addresses = HashMap::new();
+
+    // Agendas are visited in block order, so no task goes before the block of the previous one
+    let mut new_block = 1;
+    for (old_block, value) in old_agendas {
+        new_block = new_block.max(old_block.saturating_sub(offset).max(1));
+        let mut input = &value[..];
+        let len = Compact::<u32>::decode(&mut input)?.0;
+        for index in 0..len {
+            let start = input;
+            scale_json::decode(types, task_type, &mut input).map_err(|e| {
+                format!(
+                    "Failed to decode task {} of the agenda of block {}: {}",
+                    index, old_block, e
+                )
+            })?;
+            let task = &start[..start.len() - input.len()];
+
+            while merged
+                .get(&new_block)
+                .is_some_and(|(count, _)| *count >= max_per_block)
+            {
+                new_block += 1;
+            }
+            let (count, tasks) = merged.entry(new_block).or_default();
+            task_addresses.insert((old_block, index), (new_block, *count));
+            *count += 1;
+            tasks.extend_from_slice(task);
+        }
+    }
+
+    let agendas = merged
+        .into_iter()
+        .map(|(block, (count, tasks))| {
+            let mut value = Compact(count).encode();
+            value.extend(tasks);
+            (block, value)
+        })
+        .collect();
+
+    Ok(ShiftedAgendas {
+        agendas,
+        task_addresses,
+    })
+}
+
+pub async fn rebase_time<Block, HostFns>(
+    shared: SharedParams,
+    command: RebaseTimeCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let number_key = storage_prefix("System", "Number");
+    let parent_hash_key = storage_prefix("System", "ParentHash");
+    let block_hash_prefix = storage_prefix("System", "BlockHash");
+    let now_key = storage_prefix("Timestamp", "Now");
+    let current_slot_key = storage_prefix("Aura", "CurrentSlot");
+    let agenda_prefix = storage_prefix("Scheduler", "Agenda");
+    let lookup_prefix = storage_prefix("Scheduler", "Lookup");
+    let incomplete_since_key = storage_prefix("Scheduler", "IncompleteSince");
+
+    let genesis_timestamp = command.genesis_timestamp.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("System time before unix epoch")
+            .as_millis() as u64
+    });
+    let genesis_slot = genesis_timestamp / command.slot_duration;
+
+    // First pass: find the old block number, and the agendas if we need to move them
+    let (source, block) =
+        open_source_with_block::<Block>(&command.state_path, &command.rpc).await?;
+    let mut old_number = None;
+    let mut old_agendas = BTreeMap::new();
+    for kv in source.key_values()? {
+        let (key, value) = kv?;
+        if key == number_key {
+            old_number = Some(u32::decode(&mut &value[..])?);
+        } else if command.shift_scheduler && key.starts_with(&agenda_prefix) {
+            let Some(block) = agenda_block(&agenda_prefix, &key) else {
+                log::warn!(
+                    "Skipping invalid Scheduler::Agenda key: 0x{}",
+                    hex::encode(&key)
+                );
+                continue;
+            };
+            old_agendas.insert(block, value);
+        }
+    }
+    let old_number = old_number.unwrap_or_else(|| {
+        log::warn!("System::Number not found, assuming 0");
+        0
+    });
+
+    log::info!(
+        "Rebasing from block {} to genesis, timestamp {}, slot {}",
+        old_number,
+        genesis_timestamp,
+        genesis_slot
+    );
+
+    let shifted = if command.shift_scheduler {
+        let code = runtime_code(&shared, || source.code())?;
+        let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+        let max_per_block = runtime.constant::<u32>("Scheduler", "MaxScheduledPerBlock")?;
+        let task_type = agenda_task_type(&runtime)?;
+        let shifted = shift_agendas(
+            &runtime.types,
+            task_type,
+            old_agendas,
+            old_number,
+            max_per_block,
+        )?;
+        log::info!(
+            "Moved {} scheduled tasks into {} agendas",
+            shifted.task_addresses.len(),
+            shifted.agendas.len()
+        );
+        Some(shifted)
+    } else {
+        None
+    };
+
+    let mut insert = BTreeMap::from([
+        (number_key.clone(), 0u32.encode()),
+        (parent_hash_key, HASH69.to_vec()),
+        (
+            twox64_concat_key(&block_hash_prefix, &0u32.encode()),
+            HASH69.to_vec(),
+        ),
+    ]);
+    if let Some(shifted) = &shifted {
+        for (block, value) in &shifted.agendas {
+            insert.insert(
+                twox64_concat_key(&agenda_prefix, &block.encode()),
+                value.clone(),
+            );
+        }
+    }
+
+    let mut count_removed_block_hashes = 0u64;
+    let output_path = command
+        .output_path
+        .unwrap_or_else(|| command.state_path.clone());
+    let rewrite = |key: Vec<u8>, value: Vec<u8>| {
+        if key == now_key {
+            return Some((key, genesis_timestamp.encode()));
+        }
+        if key == current_slot_key {
+            return Some((key, genesis_slot.encode()));
+        }
+        if key.starts_with(&block_hash_prefix) {
+            // Only the genesis block hash is kept, it is in `insert`
+            count_removed_block_hashes += 1;
+            return None;
+        }
+        let Some(shifted) = &shifted else {
+            return Some((key, value));
+        };
+        if agenda_block(&agenda_prefix, &key).is_some() || key == incomplete_since_key {
+            // New agendas are in `insert`, the overdue tasks are now in the first blocks
+            return None;
+        }
+        if key.starts_with(&lookup_prefix) {
+            let Ok(address) = <(u32, u32)>::decode(&mut &value[..]) else {
+                log::warn!(
+                    "Failed to decode Scheduler::Lookup value: 0x{}",
+                    hex::encode(&value)
+                );
+                return Some((key, value));
+            };
+            let new_address = shifted
+                .task_addresses
+                .get(&address)
+                .copied()
+                .unwrap_or_else(|| {
+                    log::warn!("Scheduler::Lookup points to unknown task {:?}", address);
+                    address
+                });
+            return Some((key, new_address.encode()));
+        }
+
+        Some((key, value))
+    };
+    rewrite_state::<Block, _>(
+        &command.state_path,
+        source.as_ref(),
+        block,
+        &output_path,
+        insert,
+        rewrite,
+    )?;
+
+    log::info!("Removed {} old block hashes", count_removed_block_hashes);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scale_info::{MetaType, Registry};
+
+    /// Encoded agenda with tasks of type `Option<Vec<u8>>`, which have different lengths.
+    fn agenda(tasks: &[Option<Vec<u8>>]) -> Vec<u8> {
+        tasks.to_vec().encode()
+    }
+
+    #[test]
+    fn parses_agenda_keys() {
+        let prefix = storage_prefix("Scheduler", "Agenda");
+        let key = twox64_concat_key(&prefix, &42u32.encode());
+        assert_eq!(agenda_block(&prefix, &key), Some(42));
+        assert_eq!(agenda_block(&prefix, &prefix), None);
+        assert_eq!(agenda_block(&prefix, &key[..key.len() - 1]), None);
+        assert_eq!(agenda_block(&prefix, &[&key[..], &[0]].concat()), None);
+        assert_eq!(
+            agenda_block(&storage_prefix("Scheduler", "Lookup"), &key),
+            None
+        );
+    }
+    #[test]
+    fn spreads_overdue_tasks_over_next_blocks() {
+        let mut registry = Registry::new();
+        let task_type = registry
+            .register_type(&MetaType::new::<Option<Vec<u8>>>())
+            .id;
+        let types: PortableRegistry = registry.into();
+        let task = |byte: u8| Some(vec![byte; byte as usize]);
+        let old_agendas = BTreeMap::from([
+            (5, agenda(&[task(1), None, task(2)])),
+            (8, agenda(&[task(3), task(4)])),
+            (12, agenda(&[task(5)])),
+            (20, agenda(&[task(6)])),
+        ]);
+
+        let shifted = shift_agendas(&types, task_type, old_agendas, 10, 2).unwrap();
+
+        assert_eq!(
+            shifted.agendas,
+            BTreeMap::from([
+                (1, agenda(&[task(1), None])),
+                (2, agenda(&[task(2), task(3)])),
+                (3, agenda(&[task(4), task(5)])),
+                (10, agenda(&[task(6)])),
+            ])
+        );
+        assert_eq!(shifted.task_addresses[&(5, 2)], (2, 0));
+        assert_eq!(shifted.task_addresses[&(8, 1)], (3, 0));
+        assert_eq!(shifted.task_addresses[&(12, 0)], (3, 1));
+        assert_eq!(shifted.task_addresses[&(20, 0)], (10, 0));
+        assert_eq!(shifted.task_addresses.len(), 7);
+
+        let truncated = BTreeMap::from([(5, agenda(&[task(1)])[..2].to_vec())]);
+        assert!(shift_agendas(&types, task_type, truncated, 10, 2).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! Update an existing snapshot to a newer block of the same chain. Read proofs of every storage
+//! item are requested at the new block, and only the items whose trie changed are downloaded
+//! again, the rest is copied from the old snapshot.
+
+use crate::create_snapshot::{hex_param, normalize_prefixes, parse_hex, Rpc};
+use crate::metadata::{storage_prefix, RuntimeInfo};
+use crate::parse;
+use crate::snapshot::{prefix_pairs, Snapshot, SnapshotBackend};
+use crate::BlockT;
+use frame_metadata::RuntimeMetadataPrefixed;
+use jsonrpsee::rpc_params;
+use parity_scale_codec::Decode;
+use sc_executor::HostFunctions;
+use serde_json::Value;
+use sp_runtime::app_crypto::sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_runtime::app_crypto::sp_core::storage::ChildInfo;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use sp_runtime::{Storage, StorageChild};
+use sp_state_machine::{Backend, IterArgs, TrieBackendBuilder};
+use sp_trie::{HashDBT, EMPTY_PREFIX};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Keys are grouped by storage item, which is the first 32 bytes of the key. Shorter keys, like
+/// `:code`, are a group by themselves.
+const GROUP_PREFIX_LEN: usize = 32;
+
+/// Item name of the key where each pallet stores its storage version.
+const STORAGE_VERSION_KEY: &str = ":__STORAGE_VERSION__:";
+
+/// Configurations for [`refresh_snapshot`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct RefreshSnapshotCommand {
+    /// The RPC url of the node. `ws://` and `wss://` are replaced with `http://` and `https://`.
+    #[clap(long, default_value = "ws://127.0.0.1:9944")]
+    pub uri: String,
+
+    /// The block hash at which to fetch the state. Defaults to the latest finalized head.
+    #[arg(long, value_parser = parse::hash)]
+    pub at: Option<String>,
+
+    /// The snapshot to update. Must be a full snapshot of the same chain, created with
+    /// `create-snapshot` without `--pallet` or `--prefix`.
+    #[clap(long)]
+    pub old_snapshot_path: PathBuf,
+
+    /// Number of keys to fetch in each request.
+    #[clap(long, default_value_t = 1000)]
+    pub batch_size: u32,
+
+    /// Number of times to retry a failed request before giving up.
+    #[clap(long, default_value_t = 12)]
+    pub retries: u32,
+
+    /// The snapshot path to write.
+    pub snapshot_path: PathBuf,
+}
+
+/// Group prefixes of the old keys, of all the storage items and storage versions of the new
+/// runtime, and of the child tries, so that new storage items, pallets and child tries are also
+/// downloaded.
+fn key_groups<'a>(
+    old_keys: impl Iterator<Item = &'a Vec<u8>>,
+    runtime: &RuntimeInfo,
+) -> Vec<Vec<u8>> {
+    let mut groups: Vec<Vec<u8>> = old_keys
+        .map(|key| key[..key.len().min(GROUP_PREFIX_LEN)].to_vec())
+        .collect();
+    groups.extend(runtime.storage_items().map(|item| item.prefix()));
+    groups.extend(
+        runtime
+            .pallets
+            .iter()
+            .map(|pallet| storage_prefix(&pallet.name, STORAGE_VERSION_KEY)),
+    );
+    groups.push(DEFAULT_CHILD_STORAGE_KEY_PREFIX.to_vec());
+
+    normalize_prefixes(groups)
+}
+
+pub async fn refresh_snapshot<Block, HostFns>(
+    _shared: SharedParams,
+    command: RefreshSnapshotCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let rpc = Rpc::new(&command.uri, command.retries)?;
+
+    let old = Snapshot::<Block>::read(&command.old_snapshot_path).inspect_err(|e| {
+        log::error!("Failed to read old snapshot: {}", e);
+    })?;
+    let old_backend = old.backend();
+    let old_top = prefix_pairs::<Block>(&old_backend, &[])
+        .map_err(|e| format!("Old snapshot is incomplete: {}", e))?;
+    log::info!(
+        "Old snapshot is at block #{} with {} keys",
+        old.header.number(),
+        old_top.len()
+    );
+
+    let at = match &command.at {
+        Some(at) => format!("0x{}", at),
+        None => rpc.finalized_head().await?,
+    };
+    let header: Block::Header = rpc.header(&at).await?;
+    let state_version = rpc.state_version(&at).await?;
+    log::info!("Refreshing state to block #{} {}", header.number(), at);
+
+    let metadata: String = rpc.request("state_getMetadata", rpc_params![&at]).await?;
+    let metadata = RuntimeMetadataPrefixed::decode(&mut &parse_hex(&metadata)?[..])
+        .map_err(|e| format!("Failed to decode metadata: {}", e))?;
+    let runtime = RuntimeInfo::from_metadata(metadata)?;
+    let groups = key_groups(old_top.iter().map(|(key, _)| key), &runtime);
+
+    // The proofs contain the nodes from the new root down to each group. If the subtrie of a
+    // group did not change, the rest of its nodes are already in the old snapshot.
+    let mut db = old_backend.into_storage();
+    for chunk in groups.chunks(command.batch_size as usize) {
+        let keys: Vec<String> = chunk.iter().map(|prefix| hex_param(prefix)).collect();
+        let proof: Value = rpc
+            .request("state_getReadProof", rpc_params![keys, &at])
+            .await?;
+        let nodes = proof
+            .get("proof")
+            .and_then(|nodes| nodes.as_array())
+            .ok_or_else(|| format!("Unexpected state_getReadProof result: {}", proof))?;
+        for node in nodes {
+            let node = parse_hex(node.as_str().ok_or("Invalid proof node")?)?;
+            db.insert(EMPTY_PREFIX, &node);
+        }
+    }
+    let backend: SnapshotBackend<Block> = TrieBackendBuilder::new(db, *header.state_root()).build();
+
+    let mut top = BTreeMap::new();
+    let mut changed = 0;
+    for prefix in &groups {
+        match prefix_pairs(&backend, prefix) {
+            Ok(key_values) => top.extend(key_values),
+            Err(_) => {
+                // Some node under this prefix is new, download it again
+                changed += 1;
+                let key_values = rpc.fetch_prefix(prefix, command.batch_size, &at).await?;
+                log::info!(
+                    "Downloaded {} keys of changed prefix {}",
+                    key_values.len(),
+                    hex_param(prefix)
+                );
+                top.extend(key_values);
+            }
+        }
+    }
+    log::info!(
+        "{} of {} storage items changed, {} keys in total",
+        changed,
+        groups.len(),
+        top.len()
+    );
+
+    // Child tries that did not change are complete in the backend, the others are downloaded
+    // again
+    let mut children_default = HashMap::new();
+    let child_keys = top
+        .range(DEFAULT_CHILD_STORAGE_KEY_PREFIX.to_vec()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(DEFAULT_CHILD_STORAGE_KEY_PREFIX));
+    for child_key in child_keys {
+        let storage_key = &child_key[DEFAULT_CHILD_STORAGE_KEY_PREFIX.len()..];
+        let child_info = ChildInfo::new_default(storage_key);
+        let args = IterArgs {
+            child_info: Some(child_info.clone()),
+            ..Default::default()
+        };
+        let data = match backend.pairs(args).and_then(|pairs| pairs.collect()) {
+            Ok(data) => data,
+            Err(_) => {
+                let data = rpc
+                    .fetch_child_trie(child_key, command.batch_size, &at)
+                    .await?;
+                log::info!(
+                    "Downloaded {} keys of changed child trie {}",
+                    data.len(),
+                    hex_param(storage_key)
+                );
+                data
+            }
+        };
+        children_default.insert(storage_key.to_vec(), StorageChild { data, child_info });
+    }
+    let storage = Storage {
+        top,
+        children_default,
+    };
+
+    let expected_root = *header.state_root();
+    let snapshot = Snapshot::<Block>::from_storage(storage, state_version, header);
+    if snapshot.storage_root != expected_root {
+        return Err(format!(
+            "Refreshed state has storage root {:?} but block has {:?}. Some keys are not under a \
+             known storage item, use create-snapshot instead",
+            snapshot.storage_root, expected_root
+        )
+        .into());
+    }
+    snapshot.write(&command.snapshot_path)?;
+
+    Ok(())
+}
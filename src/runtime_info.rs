@@ -0,0 +1,159 @@
+use crate::metadata::{fetch_metadata, fetch_runtime_version, runtime_code, RuntimeInfo};
+use crate::state_source::{open_source, RpcConfig};
+use crate::BlockT;
+use frame_metadata::v14::StorageEntryType;
+use sc_executor::HostFunctions;
+use scale_info::TypeDef;
+use sp_runtime::traits::NumberFor;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`dump_metadata`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct MetadataCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to read `:code` from. Not
+    /// needed if `--runtime` is a path.
+    #[clap(long)]
+    pub state_path: Option<String>,
+
+    /// Print a compact list of pallets with their storage items and calls, instead of the full
+    /// JSON metadata.
+    #[clap(long)]
+    pub summary: bool,
+
+    /// Output path. Defaults to stdout.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// Configurations for [`runtime_version`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct RuntimeVersionCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to read `:code` from. Not
+    /// needed if `--runtime` is a path.
+    #[clap(long)]
+    pub state_path: Option<String>,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+async fn load_code<Block, HostFns>(
+    shared: &SharedParams,
+    state_path: &Option<String>,
+    rpc: &RpcConfig,
+) -> sc_cli::Result<Vec<u8>>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let input = match state_path {
+        Some(state_path) => Some(open_source::<Block>(state_path, rpc).await?),
+        None => None,
+    };
+    let code = runtime_code(shared, || match &input {
+        Some(input) => input.code(),
+        None => Ok(None),
+    })?;
+
+    Ok(code)
+}
+
+fn write_summary(output: &mut dyn Write, runtime: &RuntimeInfo) -> io::Result<()> {
+    for pallet in &runtime.pallets {
+        writeln!(output, "{:>3} {}", pallet.index, pallet.name)?;
+        if let Some(storage) = &pallet.storage {
+            for entry in &storage.entries {
+                match &entry.ty {
+                    StorageEntryType::Plain(_) => {
+                        writeln!(output, "      storage {}", entry.name)?;
+                    }
+                    StorageEntryType::Map { hashers, .. } => {
+                        writeln!(output, "      storage {} {:?}", entry.name, hashers)?;
+                    }
+                }
+            }
+        }
+        let calls = pallet.calls.and_then(|ty| runtime.types.resolve(ty));
+        if let Some(TypeDef::Variant(calls)) = calls.map(|ty| &ty.type_def) {
+            for call in &calls.variants {
+                writeln!(output, "      call {} {}", call.index, call.name)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn dump_metadata<Block, HostFns>(
+    shared: SharedParams,
+    command: MetadataCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let code = load_code::<Block, HostFns>(&shared, &command.state_path, &command.rpc).await?;
+    let metadata = fetch_metadata::<HostFns>(&shared, &code)?;
+
+    let mut output: Box<dyn Write> = match command.output_path {
+        Some(output_path) => Box::new(BufWriter::new(File::create(output_path).inspect_err(
+            |e| {
+                log::error!("Failed to create output file: {}", e);
+            },
+        )?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    if command.summary {
+        let runtime = RuntimeInfo::from_metadata(metadata)?;
+        write_summary(&mut output, &runtime)?;
+    } else {
+        serde_json::to_writer_pretty(&mut output, &metadata)
+            .map_err(|e| format!("Failed to serialize metadata: {}", e))?;
+        writeln!(output)?;
+    }
+    output.flush()?;
+
+    Ok(())
+}
+
+pub async fn runtime_version<Block, HostFns>(
+    shared: SharedParams,
+    command: RuntimeVersionCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let code = load_code::<Block, HostFns>(&shared, &command.state_path, &command.rpc).await?;
+    let version = fetch_runtime_version::<HostFns>(&shared, &code)?;
+
+    let json = serde_json::to_string_pretty(&version)
+        .map_err(|e| format!("Failed to serialize runtime version: {}", e))?;
+    println!("{}", json);
+
+    Ok(())
+}
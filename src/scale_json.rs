@@ -0,0 +1,819 @@
+//! Convert between SCALE-encoded values and JSON using the type information from the metadata.
+//!
+//! The JSON format is:
+//! * Numbers can be JSON numbers or strings, big numbers are decoded as strings.
+//! * `Vec<u8>` and `[u8; N]` are hex strings with `0x` prefix. `[u8; 32]` can also be a SS58
+//!   address.
+//! * Structs with named fields are objects, tuple structs are arrays, and structs with only one
+//!   field are the same as that field, so `AccountId32` is just a hex string.
+//! * Enum variants without fields are strings, other variants are `{"Name": fields}`.
+//! * `Option` is `null` or the inner value.
+
+use parity_scale_codec::{Compact, Decode, Encode};
+use scale_info::form::PortableForm;
+use scale_info::{Field, PortableRegistry, Type, TypeDef, TypeDefPrimitive};
+use serde_json::{Map, Value};
+use sp_runtime::AccountId32;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Parse a JSON command line argument. Anything that is not valid JSON is treated as a string,
+/// to avoid having to quote addresses and hex strings.
+pub fn parse_json_arg(arg: &str) -> Value {
+    serde_json::from_str(arg).unwrap_or_else(|_| Value::String(arg.to_string()))
+}
+
+static NULL: Value = Value::Null;
+
+fn resolve(types: &PortableRegistry, ty: u32) -> Result<&Type<PortableForm>, String> {
+    types
+        .resolve(ty)
+        .ok_or_else(|| format!("Type {} not found in metadata", ty))
+}
+
+fn is_option(ty: &Type<PortableForm>) -> bool {
+    ty.path.segments == ["Option"]
+}
+
+fn is_u8(types: &PortableRegistry, ty: u32) -> bool {
+    matches!(
+        types.resolve(ty).map(|t| &t.type_def),
+        Some(TypeDef::Primitive(TypeDefPrimitive::U8))
+    )
+}
+
+/// SCALE-encode a JSON value as the type `ty`.
+pub fn encode(types: &PortableRegistry, ty: u32, value: &Value) -> Result<Vec<u8>, String> {
+    let mut out = vec![];
+    encode_into(types, ty, value, &mut out)?;
+
+    Ok(out)
+}
+
+fn encode_into(
+    types: &PortableRegistry,
+    ty: u32,
+    value: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), String> {
+    let type_info = resolve(types, ty)?;
+
+    match &type_info.type_def {
+        TypeDef::Composite(composite) => encode_fields(types, &composite.fields, value, out),
+        TypeDef::Variant(variant) => {
+            let (name, fields_value) = if is_option(type_info) {
+                match value {
+                    Value::Null => ("None", &NULL),
+                    value => ("Some", value),
+                }
+            } else {
+                match value {
+                    Value::String(name) => (name.as_str(), &NULL),
+                    Value::Object(map) if map.len() == 1 => {
+                        let (name, fields_value) = map.iter().next().unwrap();
+                        (name.as_str(), fields_value)
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Expected enum variant as \"Name\" or {{\"Name\": fields}}, found: {}",
+                            value
+                        ))
+                    }
+                }
+            };
+            let variant = variant
+                .variants
+                .iter()
+                .find(|v| v.name == name)
+                .ok_or_else(|| format!("Unknown enum variant: {}", name))?;
+            out.push(variant.index);
+            encode_fields(types, &variant.fields, fields_value, out)
+        }
+        TypeDef::Sequence(sequence) => {
+            if is_u8(types, sequence.type_param.id) {
+                if let Value::String(s) = value {
+                    let bytes = parse_hex(s)?;
+                    bytes.encode_to(out);
+                    return Ok(());
+                }
+            }
+            let items = value
+                .as_array()
+                .ok_or_else(|| format!("Expected array, found: {}", value))?;
+            Compact(items.len() as u32).encode_to(out);
+            for item in items {
+                encode_into(types, sequence.type_param.id, item, out)?;
+            }
+            Ok(())
+        }
+        TypeDef::Array(array) => {
+            if is_u8(types, array.type_param.id) {
+                if let Value::String(s) = value {
+                    let bytes = if s.starts_with("0x") || array.len != 32 {
+                        parse_hex(s)?
+                    } else {
+                        <[u8; 32]>::from(AccountId32::from_str(s).map_err(|e| {
+                            format!("Expected hex string or SS58 address, found {}: {}", s, e)
+                        })?)
+                        .to_vec()
+                    };
+                    if bytes.len() != array.len as usize {
+                        return Err(format!(
+                            "Expected {} bytes, found {}: {}",
+                            array.len,
+                            bytes.len(),
+                            s
+                        ));
+                    }
+                    out.extend(bytes);
+                    return Ok(());
+                }
+            }
+            let items = value
+                .as_array()
+                .ok_or_else(|| format!("Expected array, found: {}", value))?;
+            if items.len() != array.len as usize {
+                return Err(format!(
+                    "Expected array of length {}, found: {}",
+                    array.len, value
+                ));
+            }
+            for item in items {
+                encode_into(types, array.type_param.id, item, out)?;
+            }
+            Ok(())
+        }
+        TypeDef::Tuple(tuple) => {
+            if tuple.fields.is_empty() && value.is_null() {
+                return Ok(());
+            }
+            let items = value
+                .as_array()
+                .filter(|items| items.len() == tuple.fields.len())
+                .ok_or_else(|| {
+                    format!(
+                        "Expected array of length {}, found: {}",
+                        tuple.fields.len(),
+                        value
+                    )
+                })?;
+            for (field, item) in tuple.fields.iter().zip(items) {
+                encode_into(types, field.id, item, out)?;
+            }
+            Ok(())
+        }
+        TypeDef::Primitive(primitive) => encode_primitive(primitive, value, out),
+        TypeDef::Compact(compact) => encode_compact(types, compact.type_param.id, value, out),
+        TypeDef::BitSequence(_) => Err("Encoding bit sequences is not supported".to_string()),
+    }
+}
+
+fn encode_fields(
+    types: &PortableRegistry,
+    fields: &[Field<PortableForm>],
+    value: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), String> {
+    match fields {
+        [] => match value {
+            Value::Null => Ok(()),
+            Value::Array(items) if items.is_empty() => Ok(()),
+            Value::Object(map) if map.is_empty() => Ok(()),
+            _ => Err(format!("Expected no fields, found: {}", value)),
+        },
+        // A single unnamed field is encoded as the field itself
+        [field] if field.name.is_none() => encode_into(types, field.ty.id, value, out),
+        fields if fields.iter().all(|f| f.name.is_some()) => {
+            let map = value
+                .as_object()
+                .ok_or_else(|| format!("Expected object, found: {}", value))?;
+            for field in fields {
+                let name = field.name.as_ref().unwrap();
+                let field_value = map
+                    .get(name)
+                    .ok_or_else(|| format!("Missing field {} in {}", name, value))?;
+                encode_into(types, field.ty.id, field_value, out)?;
+            }
+            if let Some(unknown) = map
+                .keys()
+                .find(|k| !fields.iter().any(|f| f.name.as_deref() == Some(k.as_str())))
+            {
+                return Err(format!("Unknown field {} in {}", unknown, value));
+            }
+            Ok(())
+        }
+        fields => {
+            let items = value
+                .as_array()
+                .filter(|items| items.len() == fields.len())
+                .ok_or_else(|| {
+                    format!(
+                        "Expected array of length {}, found: {}",
+                        fields.len(),
+                        value
+                    )
+                })?;
+            for (field, item) in fields.iter().zip(items) {
+                encode_into(types, field.ty.id, item, out)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn encode_primitive(
+    primitive: &TypeDefPrimitive,
+    value: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), String> {
+    match primitive {
+        TypeDefPrimitive::Bool => value
+            .as_bool()
+            .ok_or_else(|| format!("Expected bool, found: {}", value))?
+            .encode_to(out),
+        TypeDefPrimitive::Char => {
+            let s = value
+                .as_str()
+                .ok_or_else(|| format!("Expected char, found: {}", value))?;
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => (c as u32).encode_to(out),
+                _ => return Err(format!("Expected a single char, found: {}", value)),
+            }
+        }
+        TypeDefPrimitive::Str => value
+            .as_str()
+            .ok_or_else(|| format!("Expected string, found: {}", value))?
+            .encode_to(out),
+        TypeDefPrimitive::U8 => to_unsigned::<u8>(value)?.encode_to(out),
+        TypeDefPrimitive::U16 => to_unsigned::<u16>(value)?.encode_to(out),
+        TypeDefPrimitive::U32 => to_unsigned::<u32>(value)?.encode_to(out),
+        TypeDefPrimitive::U64 => to_unsigned::<u64>(value)?.encode_to(out),
+        TypeDefPrimitive::U128 => to_unsigned::<u128>(value)?.encode_to(out),
+        TypeDefPrimitive::I8 => to_signed::<i8>(value)?.encode_to(out),
+        TypeDefPrimitive::I16 => to_signed::<i16>(value)?.encode_to(out),
+        TypeDefPrimitive::I32 => to_signed::<i32>(value)?.encode_to(out),
+        TypeDefPrimitive::I64 => to_signed::<i64>(value)?.encode_to(out),
+        TypeDefPrimitive::I128 => to_signed::<i128>(value)?.encode_to(out),
+        TypeDefPrimitive::U256 | TypeDefPrimitive::I256 => {
+            // Little endian, same as the encoding
+            let s = value
+                .as_str()
+                .ok_or_else(|| format!("Expected hex string, found: {}", value))?;
+            let bytes = parse_hex(s)?;
+            if bytes.len() != 32 {
+                return Err(format!("Expected 32 bytes, found {}: {}", bytes.len(), s));
+            }
+            out.extend(bytes);
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_compact(
+    types: &PortableRegistry,
+    ty: u32,
+    value: &Value,
+    out: &mut Vec<u8>,
+) -> Result<(), String> {
+    let type_info = resolve(types, ty)?;
+
+    match &type_info.type_def {
+        TypeDef::Primitive(TypeDefPrimitive::U8) => {
+            Compact(to_unsigned::<u8>(value)?).encode_to(out)
+        }
+        TypeDef::Primitive(TypeDefPrimitive::U16) => {
+            Compact(to_unsigned::<u16>(value)?).encode_to(out)
+        }
+        TypeDef::Primitive(TypeDefPrimitive::U32) => {
+            Compact(to_unsigned::<u32>(value)?).encode_to(out)
+        }
+        TypeDef::Primitive(TypeDefPrimitive::U64) => {
+            Compact(to_unsigned::<u64>(value)?).encode_to(out)
+        }
+        TypeDef::Primitive(TypeDefPrimitive::U128) => {
+            Compact(to_unsigned::<u128>(value)?).encode_to(out)
+        }
+        // Things like `Compact<Perbill>`
+        TypeDef::Composite(composite) if composite.fields.len() == 1 => {
+            return encode_compact(types, composite.fields[0].ty.id, value, out)
+        }
+        TypeDef::Tuple(tuple) if tuple.fields.is_empty() => {}
+        _ => return Err(format!("Unsupported compact type: {:?}", type_info.path)),
+    }
+
+    Ok(())
+}
+
+fn to_unsigned<T: TryFrom<u128>>(value: &Value) -> Result<T, String> {
+    let n = match value {
+        Value::Number(n) => n.as_u64().map(u128::from),
+        Value::String(s) => u128::from_str(s).ok(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("Expected unsigned integer, found: {}", value))?;
+
+    T::try_from(n).map_err(|_| format!("Integer out of range: {}", value))
+}
+
+fn to_signed<T: TryFrom<i128>>(value: &Value) -> Result<T, String> {
+    let n = match value {
+        Value::Number(n) => n.as_i64().map(i128::from),
+        Value::String(s) => i128::from_str(s).ok(),
+        _ => None,
+    }
+    .ok_or_else(|| format!("Expected integer, found: {}", value))?;
+
+    T::try_from(n).map_err(|_| format!("Integer out of range: {}", value))
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    hex::decode(s.strip_prefix("0x").unwrap_or(s))
+        .map_err(|e| format!("Expected hex string, found {}: {}", s, e))
+}
+
+/// Decode a SCALE-encoded value of type `ty` as JSON, advancing `input`.
+pub fn decode(types: &PortableRegistry, ty: u32, input: &mut &[u8]) -> Result<Value, String> {
+    let type_info = resolve(types, ty)?;
+
+    match &type_info.type_def {
+        TypeDef::Composite(composite) => decode_fields(types, &composite.fields, input),
+        TypeDef::Variant(variant) => {
+            let index = u8::decode(input).map_err(|e| e.to_string())?;
+            let variant = variant
+                .variants
+                .iter()
+                .find(|v| v.index == index)
+                .ok_or_else(|| {
+                    format!("Unknown variant index {} of {:?}", index, type_info.path)
+                })?;
+            let fields = decode_fields(types, &variant.fields, input)?;
+            if is_option(type_info) {
+                return Ok(fields);
+            }
+            if variant.fields.is_empty() {
+                Ok(Value::String(variant.name.clone()))
+            } else {
+                let mut map = Map::new();
+                map.insert(variant.name.clone(), fields);
+                Ok(Value::Object(map))
+            }
+        }
+        TypeDef::Sequence(sequence) => {
+            let len = Compact::<u32>::decode(input).map_err(|e| e.to_string())?.0 as usize;
+            if is_u8(types, sequence.type_param.id) {
+                return Ok(Value::String(format!(
+                    "0x{}",
+                    hex::encode(take(input, len)?)
+                )));
+            }
+            let items = (0..len)
+                .map(|_| decode(types, sequence.type_param.id, input))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Value::Array(items))
+        }
+        TypeDef::Array(array) => {
+            if is_u8(types, array.type_param.id) {
+                let bytes = take(input, array.len as usize)?;
+                return Ok(Value::String(format!("0x{}", hex::encode(bytes))));
+            }
+            let items = (0..array.len)
+                .map(|_| decode(types, array.type_param.id, input))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Value::Array(items))
+        }
+        TypeDef::Tuple(tuple) => {
+            if tuple.fields.is_empty() {
+                return Ok(Value::Null);
+            }
+            let items = tuple
+                .fields
+                .iter()
+                .map(|field| decode(types, field.id, input))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Value::Array(items))
+        }
+        TypeDef::Primitive(primitive) => decode_primitive(primitive, input),
+        TypeDef::Compact(compact) => decode_compact(types, compact.type_param.id, input),
+        TypeDef::BitSequence(bits) => {
+            let len = Compact::<u32>::decode(input).map_err(|e| e.to_string())?.0 as usize;
+            let store_bits = match &resolve(types, bits.bit_store_type.id)?.type_def {
+                TypeDef::Primitive(TypeDefPrimitive::U8) => 8,
+                TypeDef::Primitive(TypeDefPrimitive::U16) => 16,
+                TypeDef::Primitive(TypeDefPrimitive::U32) => 32,
+                TypeDef::Primitive(TypeDefPrimitive::U64) => 64,
+                _ => return Err("Unsupported bit sequence store type".to_string()),
+            };
+            let bytes = len.div_ceil(store_bits) * store_bits / 8;
+            Ok(Value::String(format!(
+                "0x{}",
+                hex::encode(take(input, bytes)?)
+            )))
+        }
+    }
+}
+
+/// Decode a SCALE-encoded value of type `ty` as JSON, failing if there are any bytes left.
+pub fn decode_all(types: &PortableRegistry, ty: u32, mut input: &[u8]) -> Result<Value, String> {
+    let value = decode(types, ty, &mut input)?;
+    if !input.is_empty() {
+        return Err(format!("{} bytes left after decoding", input.len()));
+    }
+
+    Ok(value)
+}
+
+fn decode_fields(
+    types: &PortableRegistry,
+    fields: &[Field<PortableForm>],
+    input: &mut &[u8],
+) -> Result<Value, String> {
+    match fields {
+        [] => Ok(Value::Null),
+        [field] if field.name.is_none() => decode(types, field.ty.id, input),
+        fields if fields.iter().all(|f| f.name.is_some()) => {
+            let mut map = Map::new();
+            for field in fields {
+                map.insert(
+                    field.name.clone().unwrap(),
+                    decode(types, field.ty.id, input)?,
+                );
+            }
+            Ok(Value::Object(map))
+        }
+        fields => {
+            let items = fields
+                .iter()
+                .map(|field| decode(types, field.ty.id, input))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Value::Array(items))
+        }
+    }
+}
+
+fn decode_primitive(primitive: &TypeDefPrimitive, input: &mut &[u8]) -> Result<Value, String> {
+    fn dec<T: Decode>(input: &mut &[u8]) -> Result<T, String> {
+        T::decode(input).map_err(|e| e.to_string())
+    }
+
+    Ok(match primitive {
+        TypeDefPrimitive::Bool => Value::Bool(dec(input)?),
+        TypeDefPrimitive::Char => {
+            let c = char::from_u32(dec(input)?).ok_or("Invalid char")?;
+            Value::String(c.to_string())
+        }
+        TypeDefPrimitive::Str => Value::String(dec(input)?),
+        TypeDefPrimitive::U8 => Value::from(dec::<u8>(input)?),
+        TypeDefPrimitive::U16 => Value::from(dec::<u16>(input)?),
+        TypeDefPrimitive::U32 => Value::from(dec::<u32>(input)?),
+        TypeDefPrimitive::U64 => Value::from(dec::<u64>(input)?),
+        TypeDefPrimitive::U128 => unsigned_to_json(dec::<u128>(input)?),
+        TypeDefPrimitive::I8 => Value::from(dec::<i8>(input)?),
+        TypeDefPrimitive::I16 => Value::from(dec::<i16>(input)?),
+        TypeDefPrimitive::I32 => Value::from(dec::<i32>(input)?),
+        TypeDefPrimitive::I64 => Value::from(dec::<i64>(input)?),
+        TypeDefPrimitive::I128 => {
+            let n = dec::<i128>(input)?;
+            match i64::try_from(n) {
+                Ok(n) => Value::from(n),
+                Err(_) => Value::String(n.to_string()),
+            }
+        }
+        TypeDefPrimitive::U256 | TypeDefPrimitive::I256 => {
+            Value::String(format!("0x{}", hex::encode(take(input, 32)?)))
+        }
+    })
+}
+
+fn decode_compact(types: &PortableRegistry, ty: u32, input: &mut &[u8]) -> Result<Value, String> {
+    let type_info = resolve(types, ty)?;
+
+    match &type_info.type_def {
+        TypeDef::Primitive(
+            TypeDefPrimitive::U8
+            | TypeDefPrimitive::U16
+            | TypeDefPrimitive::U32
+            | TypeDefPrimitive::U64
+            | TypeDefPrimitive::U128,
+        ) => Ok(unsigned_to_json(
+            Compact::<u128>::decode(input).map_err(|e| e.to_string())?.0,
+        )),
+        TypeDef::Composite(composite) if composite.fields.len() == 1 => {
+            decode_compact(types, composite.fields[0].ty.id, input)
+        }
+        TypeDef::Tuple(tuple) if tuple.fields.is_empty() => Ok(Value::Null),
+        _ => Err(format!("Unsupported compact type: {:?}", type_info.path)),
+    }
+}
+
+/// JSON numbers cannot hold values bigger than `u64::MAX`, use a string for those.
+fn unsigned_to_json(n: u128) -> Value {
+    match u64::try_from(n) {
+        Ok(n) => Value::from(n),
+        Err(_) => Value::String(n.to_string()),
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if input.len() < len {
+        return Err(format!(
+            "Not enough data: expected {} bytes, found {}",
+            len,
+            input.len()
+        ));
+    }
+    let (bytes, rest) = input.split_at(len);
+    *input = rest;
+
+    Ok(bytes)
+}
+
+/// Copy a SCALE-encoded value of type `ty` from `input` to `out`, passing the bytes of every
+/// inner value whose type matches `is_target` to `replace`. If `replace` returns some bytes,
+/// they are written instead of the original ones.
+pub fn substitute(
+    types: &PortableRegistry,
+    ty: u32,
+    input: &mut &[u8],
+    out: &mut Vec<u8>,
+    is_target: &dyn Fn(&Type<PortableForm>) -> bool,
+    replace: &mut dyn FnMut(&[u8]) -> Option<Vec<u8>>,
+    contains_target: &mut HashMap<u32, bool>,
+) -> Result<(), String> {
+    let type_info = resolve(types, ty)?;
+    let start = *input;
+
+    if is_target(type_info) || !contains_type(types, ty, is_target, contains_target) {
+        decode(types, ty, input)?;
+        let bytes = &start[..start.len() - input.len()];
+        match is_target(type_info).then(|| replace(bytes)).flatten() {
+            Some(new_bytes) => out.extend(new_bytes),
+            None => out.extend_from_slice(bytes),
+        }
+        return Ok(());
+    }
+
+    let mut sub = |ty: u32, input: &mut &[u8], out: &mut Vec<u8>| {
+        substitute(
+            types,
+            ty,
+            input,
+            out,
+            is_target,
+            &mut *replace,
+            &mut *contains_target,
+        )
+    };
+    match &type_info.type_def {
+        TypeDef::Composite(composite) => {
+            for field in &composite.fields {
+                sub(field.ty.id, input, out)?;
+            }
+        }
+        TypeDef::Variant(variant) => {
+            let index = u8::decode(input).map_err(|e| e.to_string())?;
+            out.push(index);
+            let variant = variant
+                .variants
+                .iter()
+                .find(|v| v.index == index)
+                .ok_or_else(|| {
+                    format!("Unknown variant index {} of {:?}", index, type_info.path)
+                })?;
+            for field in &variant.fields {
+                sub(field.ty.id, input, out)?;
+            }
+        }
+        TypeDef::Sequence(sequence) => {
+            let len = Compact::<u32>::decode(input).map_err(|e| e.to_string())?;
+            len.encode_to(out);
+            for _ in 0..len.0 {
+                sub(sequence.type_param.id, input, out)?;
+            }
+        }
+        TypeDef::Array(array) => {
+            for _ in 0..array.len {
+                sub(array.type_param.id, input, out)?;
+            }
+        }
+        TypeDef::Tuple(tuple) => {
+            for field in &tuple.fields {
+                sub(field.id, input, out)?;
+            }
+        }
+        // These cannot contain other types, so they were handled above
+        TypeDef::Primitive(_) | TypeDef::Compact(_) | TypeDef::BitSequence(_) => unreachable!(),
+    }
+
+    Ok(())
+}
+
+/// Returns true if the type `ty` or any of its inner types match `is_target`. Results are cached
+/// in `memo`.
+fn contains_type(
+    types: &PortableRegistry,
+    ty: u32,
+    is_target: &dyn Fn(&Type<PortableForm>) -> bool,
+    memo: &mut HashMap<u32, bool>,
+) -> bool {
+    contains_type_inner(types, ty, is_target, memo, &mut vec![]).0
+}
+
+/// `in_progress` is the stack of types being checked. A recursive type is assumed to not contain
+/// the target while it is being checked, so a `false` result that depends on it is provisional.
+/// Also returns the lowest position in `in_progress` that the result depends on, and only final
+/// results are cached.
+fn contains_type_inner(
+    types: &PortableRegistry,
+    ty: u32,
+    is_target: &dyn Fn(&Type<PortableForm>) -> bool,
+    memo: &mut HashMap<u32, bool>,
+    in_progress: &mut Vec<u32>,
+) -> (bool, usize) {
+    if let Some(result) = memo.get(&ty) {
+        return (*result, usize::MAX);
+    }
+    if let Some(position) = in_progress.iter().position(|t| *t == ty) {
+        return (false, position);
+    }
+    let Some(type_info) = types.resolve(ty) else {
+        return (false, usize::MAX);
+    };
+    if is_target(type_info) {
+        memo.insert(ty, true);
+        return (true, usize::MAX);
+    }
+
+    let position = in_progress.len();
+    in_progress.push(ty);
+    let mut depends_on = usize::MAX;
+    let mut check = |ty: u32| {
+        let (result, inner_depends_on) =
+            contains_type_inner(types, ty, is_target, memo, in_progress);
+        depends_on = depends_on.min(inner_depends_on);
+        result
+    };
+    let result = match &type_info.type_def {
+        TypeDef::Composite(composite) => composite.fields.iter().any(|f| check(f.ty.id)),
+        TypeDef::Variant(variant) => variant
+            .variants
+            .iter()
+            .any(|v| v.fields.iter().any(|f| check(f.ty.id))),
+        TypeDef::Sequence(sequence) => check(sequence.type_param.id),
+        TypeDef::Array(array) => check(array.type_param.id),
+        TypeDef::Tuple(tuple) => tuple.fields.iter().any(|f| check(f.id)),
+        TypeDef::Primitive(_) | TypeDef::Compact(_) | TypeDef::BitSequence(_) => false,
+    };
+    in_progress.pop();
+
+    // Finding the target is always final. Not finding it is final once all the recursive types
+    // it depends on are checked.
+    if result || depends_on >= position {
+        memo.insert(ty, result);
+        (result, usize::MAX)
+    } else {
+        (false, depends_on)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scale_info::{MetaType, Registry, TypeInfo};
+
+    #[derive(Encode, TypeInfo)]
+    struct Account([u8; 4]);
+
+    /// The recursive variant comes first, so `Vec<Tree>` is checked while `Tree` is in progress.
+    #[derive(Encode, TypeInfo)]
+    enum Tree {
+        Node(Vec<Tree>),
+        Leaf(Account),
+    }
+
+    fn registry<T: TypeInfo + 'static>() -> (PortableRegistry, u32) {
+        let mut registry = Registry::new();
+        let ty = registry.register_type(&MetaType::new::<T>()).id;
+        (registry.into(), ty)
+    }
+
+    #[derive(Encode, TypeInfo)]
+    enum Status {
+        Idle,
+        Busy { until: u32 },
+    }
+
+    #[derive(Encode, TypeInfo)]
+    struct Info {
+        nonce: u32,
+        #[codec(compact)]
+        balance: u128,
+        status: Status,
+        owner: Option<Account>,
+        data: Vec<u8>,
+    }
+
+    fn is_account(ty: &Type<PortableForm>) -> bool {
+        ty.path.ident().as_deref() == Some("Account")
+    }
+
+    #[test]
+    fn encodes_and_decodes_json() {
+        let (types, ty) = registry::<Info>();
+        let info = Info {
+            nonce: 7,
+            balance: u128::MAX,
+            status: Status::Busy { until: 10 },
+            owner: Some(Account([1, 2, 3, 4])),
+            data: vec![0xab, 0xcd],
+        };
+        let json = serde_json::json!({
+            "nonce": 7,
+            "balance": u128::MAX.to_string(),
+            "status": { "Busy": { "until": 10 } },
+            "owner": "0x01020304",
+            "data": "0xabcd",
+        });
+
+        assert_eq!(encode(&types, ty, &json).unwrap(), info.encode());
+        assert_eq!(decode_all(&types, ty, &info.encode()).unwrap(), json);
+    }
+
+    #[test]
+    fn encodes_variants_without_fields_and_none() {
+        let (types, ty) = registry::<Info>();
+        let info = Info {
+            nonce: 0,
+            balance: 5,
+            status: Status::Idle,
+            owner: None,
+            data: vec![],
+        };
+        // Numbers can also be strings
+        let json = serde_json::json!({
+            "nonce": "0",
+            "balance": 5,
+            "status": "Idle",
+            "owner": null,
+            "data": "0x",
+        });
+
+        assert_eq!(encode(&types, ty, &json).unwrap(), info.encode());
+        let decoded = decode_all(&types, ty, &info.encode()).unwrap();
+        assert_eq!(decoded["nonce"], 0);
+        assert_eq!(decoded["status"], "Idle");
+        assert_eq!(decoded["owner"], Value::Null);
+    }
+
+    #[test]
+    fn encodes_ss58_addresses() {
+        let (types, ty) = registry::<[u8; 32]>();
+        let alice = "5GrwvaEF5zXb26Fz9rcQpDWS57CtERHpNehXCPcNoHGKutQY";
+        let encoded = encode(&types, ty, &Value::from(alice)).unwrap();
+
+        assert_eq!(
+            hex::encode(&encoded),
+            "d43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_lengths_and_trailing_bytes() {
+        let (types, ty) = registry::<Account>();
+
+        assert!(encode(&types, ty, &Value::from("0x0102")).is_err());
+        assert!(decode_all(&types, ty, &[1, 2, 3, 4, 5]).is_err());
+    }
+
+    #[test]
+    fn substitutes_inside_recursive_types() {
+        let (types, ty) = registry::<Tree>();
+        let value = Tree::Node(vec![Tree::Node(vec![Tree::Leaf(Account([1; 4]))])]);
+        let expected = Tree::Node(vec![Tree::Node(vec![Tree::Leaf(Account([2; 4]))])]);
+
+        let encoded = value.encode();
+        let mut out = vec![];
+        substitute(
+            &types,
+            ty,
+            &mut &encoded[..],
+            &mut out,
+            &is_account,
+            &mut |bytes| (bytes == [1; 4]).then(|| vec![2; 4]),
+            &mut HashMap::new(),
+        )
+        .unwrap();
+
+        assert_eq!(out, expected.encode());
+    }
+
+    #[test]
+    fn caches_only_final_results() {
+        let (types, ty) = registry::<Tree>();
+        let mut memo = HashMap::new();
+
+        assert!(contains_type(&types, ty, &is_account, &mut memo));
+        assert!(memo.values().all(|contains| *contains));
+    }
+}
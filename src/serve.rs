@@ -0,0 +1,420 @@
+//! Serve the storage of a state over JSON-RPC, like a node that is stuck at one block. Only the
+//! methods needed to read storage are implemented, and the block hash arguments are ignored.
+
+use crate::create_snapshot::{hex_param, parse_hex};
+use crate::metadata::{call_runtime_api, fetch_runtime_version, runtime_code, CODE_KEY};
+use crate::snapshot::{prefix_pairs, Snapshot};
+use crate::state_file::is_snapshot;
+use crate::state_source::{open_source, RpcConfig, SnapshotSource, StateSource};
+use crate::BlockT;
+use jsonrpsee::server::{RpcModule, Server, ServerHandle};
+use jsonrpsee::types::error::{ErrorObject, ErrorObjectOwned, INVALID_PARAMS_CODE};
+use jsonrpsee::types::Params;
+use parity_scale_codec::Decode;
+use sc_executor::HostFunctions;
+use serde_json::{json, Value};
+use sp_runtime::app_crypto::sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use sp_runtime::{StateVersion, Storage};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`serve`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct ServeCommand {
+    /// The snapshot, hex snapshot or raw chain spec to serve. Snapshots must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Address to listen on, for both HTTP and WebSocket connections.
+    #[clap(long, default_value = "127.0.0.1:9944")]
+    pub listen_addr: SocketAddr,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+struct ServeState<Block: BlockT> {
+    /// Includes the keys that store the root of each child trie
+    top: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Child tries by their key in `top`
+    children: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, Vec<u8>>>,
+    header: Block::Header,
+    metadata: Vec<u8>,
+    runtime_version: Value,
+}
+
+fn invalid_params(e: impl Display) -> ErrorObjectOwned {
+    ErrorObject::owned(INVALID_PARAMS_CODE, e.to_string(), None::<()>)
+}
+
+fn parse_key(key: &str) -> Result<Vec<u8>, ErrorObjectOwned> {
+    parse_hex(key).map_err(invalid_params)
+}
+
+/// Keys of `map` that start with `prefix`, after `start_key`.
+fn keys_paged(
+    map: &BTreeMap<Vec<u8>, Vec<u8>>,
+    prefix: Option<String>,
+    count: u32,
+    start_key: Option<String>,
+) -> Result<Vec<String>, ErrorObjectOwned> {
+    let prefix = prefix
+        .as_deref()
+        .map(parse_key)
+        .transpose()?
+        .unwrap_or_default();
+    let start_key = start_key.as_deref().map(parse_key).transpose()?;
+
+    let keys = match &start_key {
+        Some(start_key) if *start_key >= prefix => map
+            .range::<Vec<u8>, _>((
+                std::ops::Bound::Excluded(start_key),
+                std::ops::Bound::Unbounded,
+            ))
+            .map(|(key, _)| key),
+        _ => map.range(prefix.clone()..).map(|(key, _)| key),
+    };
+
+    Ok(keys
+        .take_while(|key| key.starts_with(&prefix))
+        .take(count as usize)
+        .map(|key| hex_param(key))
+        .collect())
+}
+
+impl<Block: BlockT> ServeState<Block> {
+    /// Serve `storage` as the state of the block with `header`, or as a genesis block.
+    fn new(
+        storage: Storage,
+        header: Option<Block::Header>,
+        state_version: StateVersion,
+        metadata: Vec<u8>,
+        runtime_version: Value,
+    ) -> Result<Self, String> {
+        let children = storage
+            .children_default
+            .iter()
+            .map(|(storage_key, child)| {
+                let child_key = [DEFAULT_CHILD_STORAGE_KEY_PREFIX, storage_key].concat();
+                (child_key, child.data.clone())
+            })
+            .collect();
+        // The trie is built to get the roots of the child tries
+        let snapshot = match header {
+            Some(header) => Snapshot::<Block>::from_storage(storage, state_version, header),
+            None => Snapshot::<Block>::genesis(storage, state_version),
+        };
+        if snapshot.storage_root != *snapshot.header.state_root() {
+            log::warn!(
+                "State has storage root {:?} but the header has {:?}",
+                snapshot.storage_root,
+                snapshot.header.state_root()
+            );
+        }
+        let top = prefix_pairs::<Block>(&snapshot.backend(), &[])?
+            .into_iter()
+            .collect();
+
+        Ok(Self {
+            top,
+            children,
+            header: snapshot.header,
+            metadata,
+            runtime_version,
+        })
+    }
+
+    fn get_storage(&self, params: Params) -> Result<Option<String>, ErrorObjectOwned> {
+        let mut seq = params.sequence();
+        let key: String = seq.next()?;
+        Ok(self
+            .top
+            .get(&parse_key(&key)?)
+            .map(|value| hex_param(value)))
+    }
+
+    fn get_keys_paged(&self, params: Params) -> Result<Vec<String>, ErrorObjectOwned> {
+        let mut seq = params.sequence();
+        let prefix: Option<String> = seq.next()?;
+        let count: u32 = seq.next()?;
+        let start_key: Option<String> = seq.optional_next()?.flatten();
+        keys_paged(&self.top, prefix, count, start_key)
+    }
+
+    /// Values of the keys, in the format of `state_queryStorageAt`.
+    fn query_storage_at(&self, params: Params) -> Result<Value, ErrorObjectOwned> {
+        let mut seq = params.sequence();
+        let keys: Vec<String> = seq.next()?;
+        let changes = keys
+            .iter()
+            .map(|key| {
+                let value = self.top.get(&parse_key(key)?).map(|value| hex_param(value));
+                Ok(json!([key, value]))
+            })
+            .collect::<Result<Vec<_>, ErrorObjectOwned>>()?;
+
+        Ok(json!([{
+            "block": self.header.hash(),
+            "changes": changes,
+        }]))
+    }
+
+    /// Child trie with the given key in the top trie, like `:child_storage:default:` followed by
+    /// the storage key. Missing child tries are empty.
+    fn child(
+        &self,
+        child_key: &str,
+    ) -> Result<Option<&BTreeMap<Vec<u8>, Vec<u8>>>, ErrorObjectOwned> {
+        Ok(self.children.get(&parse_key(child_key)?))
+    }
+
+    fn get_child_storage(&self, params: Params) -> Result<Option<String>, ErrorObjectOwned> {
+        let mut seq = params.sequence();
+        let child_key: String = seq.next()?;
+        let key: String = seq.next()?;
+        let key = parse_key(&key)?;
+        Ok(self
+            .child(&child_key)?
+            .and_then(|child| child.get(&key))
+            .map(|value| hex_param(value)))
+    }
+
+    fn get_child_keys_paged(&self, params: Params) -> Result<Vec<String>, ErrorObjectOwned> {
+        let mut seq = params.sequence();
+        let child_key: String = seq.next()?;
+        let prefix: Option<String> = seq.next()?;
+        let count: u32 = seq.next()?;
+        let start_key: Option<String> = seq.optional_next()?.flatten();
+        match self.child(&child_key)? {
+            Some(child) => keys_paged(child, prefix, count, start_key),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Only the hash of the served block is known.
+    fn get_block_hash(&self, params: Params) -> Result<Option<Block::Hash>, ErrorObjectOwned> {
+        let mut seq = params.sequence();
+        let number: Option<u64> = seq.optional_next()?.flatten();
+        let served: u64 = (*self.header.number())
+            .try_into()
+            .map_err(|_| invalid_params("Block number too big"))?;
+
+        Ok((number.unwrap_or(served) == served).then(|| self.header.hash()))
+    }
+}
+
+/// Load the state to serve and the header of its block. A genesis header is built for hex
+/// snapshots and raw chain specs.
+async fn load_state<Block, HostFns>(
+    shared: &SharedParams,
+    state_path: &str,
+    rpc: &RpcConfig,
+) -> sc_cli::Result<ServeState<Block>>
+where
+    Block: BlockT,
+    HostFns: HostFunctions,
+{
+    let (storage, header, state_version) = if is_snapshot(state_path) {
+        let source = Box::new(SnapshotSource::<Block>::open(Path::new(state_path))?);
+        let header = source.header.clone();
+        let state_version = source.state_version;
+        (source.into_storage()?, Some(header), Some(state_version))
+    } else {
+        let source = open_source::<Block>(state_path, rpc).await?;
+        (source.into_storage()?, None, None)
+    };
+    log::info!(
+        "Loaded {} keys and {} child tries",
+        storage.top.len(),
+        storage.children_default.len()
+    );
+
+    let code = runtime_code(shared, || Ok(storage.top.get(CODE_KEY).cloned()))?;
+    let version = fetch_runtime_version::<HostFns>(shared, &code)?;
+    // `state_getMetadata` returns the bytes inside the `OpaqueMetadata`
+    let metadata = call_runtime_api::<HostFns>(shared, &code, "Metadata_metadata", &[])?;
+    let metadata = Vec::<u8>::decode(&mut &metadata[..])?;
+    let runtime_version = serde_json::to_value(&version).expect("Failed to serialize version");
+
+    Ok(ServeState::new(
+        storage,
+        header,
+        state_version.unwrap_or_else(|| version.state_version()),
+        metadata,
+        runtime_version,
+    )?)
+}
+
+/// Start serving the state, and return the address it listens on.
+async fn start_server<Block: BlockT>(
+    state: ServeState<Block>,
+    listen_addr: SocketAddr,
+) -> sc_cli::Result<(SocketAddr, ServerHandle)> {
+    let mut module = RpcModule::new(state);
+    let register_error = |e: jsonrpsee::core::RegisterMethodError| e.to_string();
+    module
+        .register_method("state_getStorage", |params, state, _| {
+            state.get_storage(params)
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("state_getKeysPaged", |params, state, _| {
+            state.get_keys_paged(params)
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("state_queryStorageAt", |params, state, _| {
+            state.query_storage_at(params)
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("childstate_getStorage", |params, state, _| {
+            state.get_child_storage(params)
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("childstate_getKeysPaged", |params, state, _| {
+            state.get_child_keys_paged(params)
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("state_getMetadata", |_, state, _| {
+            hex_param(&state.metadata)
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("state_getRuntimeVersion", |_, state, _| {
+            state.runtime_version.clone()
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("chain_getHeader", |_, state, _| {
+            Ok::<_, ErrorObjectOwned>(state.header.clone())
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("chain_getBlockHash", |params, state, _| {
+            state.get_block_hash(params)
+        })
+        .map_err(register_error)?;
+    module
+        .register_method("chain_getFinalizedHead", |_, state, _| {
+            Ok::<_, ErrorObjectOwned>(state.header.hash())
+        })
+        .map_err(register_error)?;
+
+    let server = Server::builder()
+        .max_response_body_size(u32::MAX)
+        .build(listen_addr)
+        .await?;
+    let local_addr = server.local_addr()?;
+
+    Ok((local_addr, server.start(module)))
+}
+
+pub async fn serve<Block, HostFns>(
+    shared: SharedParams,
+    command: ServeCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let state = load_state::<Block, HostFns>(&shared, &command.state_path, &command.rpc).await?;
+    log::info!(
+        "Serving block #{} with hash {:?}",
+        state.header.number(),
+        state.header.hash()
+    );
+
+    let (local_addr, handle) = start_server(state, command.listen_addr).await?;
+    log::info!("Listening on {}", local_addr);
+    handle.stopped().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_snapshot::{create_snapshot, CreateSnapshotCommand};
+    use crate::{Block, HostFns};
+    use clap::Parser;
+    use sp_runtime::app_crypto::sp_core::storage::ChildInfo;
+    use sp_runtime::StorageChild;
+    use sp_state_machine::Backend;
+    use std::collections::HashMap;
+
+    const CHILD: &[u8] = b"child";
+
+    #[tokio::test]
+    async fn create_snapshot_of_served_state() {
+        let top: BTreeMap<_, _> = (1u8..=5).map(|i| (vec![i], vec![i; 3])).collect();
+        let child: BTreeMap<_, _> = (1u8..=3).map(|i| (vec![0xc0, i], vec![i])).collect();
+        let mut children_default = HashMap::new();
+        children_default.insert(
+            CHILD.to_vec(),
+            StorageChild {
+                data: child.clone(),
+                child_info: ChildInfo::new_default(CHILD),
+            },
+        );
+        let storage = Storage {
+            top: top.clone(),
+            children_default,
+        };
+        let state = ServeState::<Block>::new(
+            storage,
+            None,
+            StateVersion::V1,
+            vec![],
+            json!({ "stateVersion": 1 }),
+        )
+        .unwrap();
+        let header = state.header.clone();
+        let (addr, _handle) = start_server(state, "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot_path = dir.path().join("state.snap");
+        let command = CreateSnapshotCommand {
+            uri: format!("http://{}", addr),
+            at: None,
+            pallet: vec![],
+            prefix: vec![],
+            batch_size: 2,
+            retries: 1,
+            checkpoint_dir: None,
+            keep_checkpoint: false,
+            snapshot_path: snapshot_path.clone(),
+        };
+        let shared = SharedParams::parse_from(["snap2zombie"]);
+        create_snapshot::<Block, HostFns>(shared, command)
+            .await
+            .unwrap();
+
+        let snapshot = Snapshot::<Block>::read(&snapshot_path).unwrap();
+        assert_eq!(snapshot.storage_root, *header.state_root());
+        assert_eq!(snapshot.header, header);
+        let backend = snapshot.backend();
+        for (key, value) in &top {
+            assert_eq!(backend.storage(key).unwrap().as_ref(), Some(value));
+        }
+        let child_info = ChildInfo::new_default(CHILD);
+        for (key, value) in &child {
+            let stored = backend.child_storage(&child_info, key).unwrap();
+            assert_eq!(stored.as_ref(), Some(value));
+        }
+    }
+}
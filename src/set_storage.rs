@@ -0,0 +1,193 @@
+use crate::metadata::{load_runtime_info, parse_item_path, runtime_code};
+use crate::scale_json::{encode, parse_json_arg};
+use crate::state_source::{open_source_with_block, rewrite_state, RpcConfig};
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use sp_runtime::traits::NumberFor;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`set_storage`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct SetStorageCommand {
+    /// Storage item to modify, as `Pallet::Item`.
+    pub item: String,
+
+    /// Map keys as JSON, one for each hasher of the storage item. Strings do not need quotes.
+    #[arg(long, num_args = 1..)]
+    pub key: Vec<String>,
+
+    /// New value as JSON, will be SCALE-encoded using the type from the metadata.
+    #[arg(long)]
+    pub value: String,
+
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to modify. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Output path, defaults to input path. A snapshot or a node can also be written as a
+    /// snapshot of its block if the path ends in `.snap`, otherwise as a hex snapshot.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// Configurations for [`remove_storage`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct RemoveStorageCommand {
+    /// Storage item to remove, as `Pallet::Item`.
+    pub item: String,
+
+    /// Map keys as JSON. If less keys than hashers are given, all the entries that start with
+    /// these keys will be removed. If empty, the whole map is removed.
+    #[arg(long, num_args = 1..)]
+    pub key: Vec<String>,
+
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to modify. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Output path, defaults to input path. A snapshot or a node can also be written as a
+    /// snapshot of its block if the path ends in `.snap`, otherwise as a hex snapshot.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+pub async fn set_storage<Block, HostFns>(
+    shared: SharedParams,
+    command: SetStorageCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let (source, block) =
+        open_source_with_block::<Block>(&command.state_path, &command.rpc).await?;
+    let code = runtime_code(&shared, || source.code())?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    let (pallet, item) = parse_item_path(&command.item)?;
+    let item = runtime.storage_item(&pallet, &item)?;
+    let keys = command
+        .key
+        .iter()
+        .map(|k| parse_json_arg(k))
+        .collect::<Vec<_>>();
+    if keys.len() != item.hashers().len() {
+        return Err(format!(
+            "Storage item {} takes {} keys, got {}",
+            item,
+            item.hashers().len(),
+            keys.len()
+        )
+        .into());
+    }
+    let key = item.key_from_json(&runtime.types, &keys)?;
+    let value = encode(
+        &runtime.types,
+        item.value_type(),
+        &parse_json_arg(&command.value),
+    )?;
+
+    log::info!(
+        "Setting 0x{} to 0x{}",
+        hex::encode(&key),
+        hex::encode(&value)
+    );
+
+    let output_path = command
+        .output_path
+        .unwrap_or_else(|| command.state_path.clone());
+    let insert = BTreeMap::from([(key, value)]);
+    let stats = rewrite_state::<Block, _>(
+        &command.state_path,
+        source.as_ref(),
+        block,
+        &output_path,
+        insert,
+        |k, v| Some((k, v)),
+    )?;
+
+    if stats.inserted > 0 {
+        log::info!("Inserted new key");
+    } else {
+        log::info!("Replaced existing key");
+    }
+
+    Ok(())
+}
+
+pub async fn remove_storage<Block, HostFns>(
+    shared: SharedParams,
+    command: RemoveStorageCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let (source, block) =
+        open_source_with_block::<Block>(&command.state_path, &command.rpc).await?;
+    let code = runtime_code(&shared, || source.code())?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    let (pallet, item) = parse_item_path(&command.item)?;
+    let item = runtime.storage_item(&pallet, &item)?;
+    let keys = command
+        .key
+        .iter()
+        .map(|k| parse_json_arg(k))
+        .collect::<Vec<_>>();
+    let prefix = item.key_from_json(&runtime.types, &keys)?;
+    // With all the keys we only want to remove that exact key, not other keys that start with it
+    let exact = keys.len() == item.hashers().len();
+
+    log::info!("Removing keys with prefix 0x{}", hex::encode(&prefix));
+
+    let output_path = command
+        .output_path
+        .unwrap_or_else(|| command.state_path.clone());
+    let mut count_removed_keys = 0u64;
+    rewrite_state::<Block, _>(
+        &command.state_path,
+        source.as_ref(),
+        block,
+        &output_path,
+        BTreeMap::new(),
+        |key, value| {
+            let matches = if exact {
+                key == prefix
+            } else {
+                key.starts_with(&prefix)
+            };
+            if matches {
+                count_removed_keys += 1;
+                None
+            } else {
+                Some((key, value))
+            }
+        },
+    )?;
+
+    log::info!("Removed {} keys", count_removed_keys);
+
+    Ok(())
+}
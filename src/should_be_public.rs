@@ -1,7 +1,12 @@
 //! Copies of utils from `try-runtime` that are not public
 
+use parity_scale_codec::Codec;
 use sc_cli::execution_method_from_cli;
 use sc_executor::{HeapAllocStrategy, HostFunctions, WasmExecutor, DEFAULT_HEAP_ALLOC_STRATEGY};
+use sp_externalities::Extensions;
+use sp_runtime::app_crypto::sp_core::traits::CallContext;
+use sp_runtime::app_crypto::sp_core::Hasher;
+use sp_state_machine::{OverlayedChanges, StateMachine, TestExternalities};
 use try_runtime_core::common::shared_parameters::SharedParams;
 
 pub fn build_executor<H: HostFunctions>(shared: &SharedParams) -> WasmExecutor<H> {
@@ -25,6 +30,38 @@ pub fn build_executor<H: HostFunctions>(shared: &SharedParams) -> WasmExecutor<H
         .build()
 }
 
+/// Execute the given `method` and `data` on top of `ext`, returning the results (encoded) and the
+/// state `changes` which can optionally be applied back in.
+pub fn state_machine_call<H, HostFns>(
+    ext: &TestExternalities<H>,
+    executor: &WasmExecutor<HostFns>,
+    method: &'static str,
+    data: &[u8],
+    mut extensions: Extensions,
+) -> sc_cli::Result<(OverlayedChanges<H>, Vec<u8>)>
+where
+    H: Hasher + 'static,
+    H::Out: Ord + Codec + 'static,
+    HostFns: HostFunctions,
+{
+    let mut changes = Default::default();
+    let encoded_result = StateMachine::new(
+        &ext.backend,
+        &mut changes,
+        executor,
+        method,
+        data,
+        &mut extensions,
+        &sp_state_machine::backend::BackendRuntimeCode::new(&ext.backend).runtime_code()?,
+        CallContext::Offchain,
+    )
+    .execute()
+    .map_err(|e| format!("failed to execute '{}': {}", method, e))
+    .map_err::<sc_cli::Error, _>(Into::into)?;
+
+    Ok((changes, encoded_result))
+}
+
 pub mod parse {
     pub fn hash(block_hash: &str) -> Result<String, String> {
         let (block_hash, offset) = if let Some(block_hash) = block_hash.strip_prefix("0x") {
@@ -0,0 +1,172 @@
+use crate::metadata::{load_runtime_info, runtime_code, RuntimeInfo};
+use crate::state_source::{open_source, RpcConfig};
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use sp_runtime::app_crypto::sp_core::twox_128;
+use sp_runtime::traits::NumberFor;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`size_report`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct SizeReportCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to read. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Number of largest entries to show
+    #[clap(long, default_value_t = 10)]
+    pub top: usize,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Sizes {
+    keys: u64,
+    key_bytes: u64,
+    value_bytes: u64,
+}
+
+impl Sizes {
+    fn add(&mut self, key_len: usize, value_len: usize) {
+        self.keys += 1;
+        self.key_bytes += key_len as u64;
+        self.value_bytes += value_len as u64;
+    }
+
+    /// Size of these key-values in the chain spec: `"0x{key}": "0x{value}",` plus newline.
+    fn json_bytes(&self) -> u64 {
+        2 * (self.key_bytes + self.value_bytes) + 12 * self.keys
+    }
+}
+
+/// Name of the storage item or pallet that owns the key, or the hex prefix if unknown.
+fn item_name(
+    runtime: Option<&RuntimeInfo>,
+    pallet_names: &HashMap<Vec<u8>, String>,
+    key: &[u8],
+) -> (String, String) {
+    if key.starts_with(b":") {
+        // Well known keys such as `:code`
+        let name = String::from_utf8_lossy(key).to_string();
+        return (name.clone(), name);
+    }
+    if let Some(item) = runtime.and_then(|r| r.storage_item_of_key(key)) {
+        return (item.pallet.to_string(), item.to_string());
+    }
+    let pallet = match key.get(..16).and_then(|p| pallet_names.get(p)) {
+        Some(pallet) => pallet.clone(),
+        None => format!("0x{}", hex::encode(&key[..key.len().min(16)])),
+    };
+    let item = match key.get(16..32) {
+        Some(item) => format!("{}::0x{}", pallet, hex::encode(item)),
+        None => format!("0x{}", hex::encode(key)),
+    };
+
+    (pallet, item)
+}
+
+fn print_sizes(name: &str, sizes: &Sizes) {
+    println!(
+        "{:<60} {:>10} {:>14} {:>14} {:>14}",
+        name,
+        sizes.keys,
+        sizes.key_bytes,
+        sizes.value_bytes,
+        sizes.json_bytes()
+    );
+}
+
+fn print_header(title: &str) {
+    println!();
+    println!(
+        "{:<60} {:>10} {:>14} {:>14} {:>14}",
+        title, "keys", "key bytes", "value bytes", "json bytes"
+    );
+}
+
+pub async fn size_report<Block, HostFns>(
+    shared: SharedParams,
+    command: SizeReportCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let input = open_source::<Block>(&command.state_path, &command.rpc).await?;
+    // The metadata is only used to give names to the prefixes, so it is optional
+    let runtime = match runtime_code(&shared, || input.code())
+        .and_then(|code| load_runtime_info::<HostFns>(&shared, &code))
+    {
+        Ok(runtime) => Some(runtime),
+        Err(e) => {
+            log::warn!("Storage items will not have names: {}", e);
+            None
+        }
+    };
+    let pallet_names: HashMap<Vec<u8>, String> = runtime
+        .iter()
+        .flat_map(|r| r.pallets.iter())
+        .filter_map(|p| p.storage.as_ref())
+        .map(|s| (twox_128(s.prefix.as_bytes()).to_vec(), s.prefix.clone()))
+        .collect();
+
+    let mut total = Sizes::default();
+    let mut pallets: BTreeMap<String, Sizes> = BTreeMap::new();
+    let mut items: BTreeMap<String, Sizes> = BTreeMap::new();
+    // Min-heap with the largest entries
+    let mut largest: BinaryHeap<Reverse<(usize, Vec<u8>)>> = BinaryHeap::new();
+
+    for kv in input.key_values()? {
+        let (key, value) = kv?;
+        let (pallet, item) = item_name(runtime.as_ref(), &pallet_names, &key);
+        total.add(key.len(), value.len());
+        pallets
+            .entry(pallet)
+            .or_default()
+            .add(key.len(), value.len());
+        items.entry(item).or_default().add(key.len(), value.len());
+
+        largest.push(Reverse((key.len() + value.len(), key)));
+        if largest.len() > command.top {
+            largest.pop();
+        }
+    }
+
+    let mut pallets = pallets.into_iter().collect::<Vec<_>>();
+    pallets.sort_by_key(|(_, sizes)| Reverse(sizes.json_bytes()));
+    print_header("pallet");
+    for (name, sizes) in &pallets {
+        print_sizes(name, sizes);
+    }
+
+    let mut items = items.into_iter().collect::<Vec<_>>();
+    items.sort_by_key(|(_, sizes)| Reverse(sizes.json_bytes()));
+    print_header("storage item");
+    for (name, sizes) in &items {
+        print_sizes(name, sizes);
+    }
+
+    print_header("total");
+    print_sizes("", &total);
+
+    println!();
+    println!("Largest {} entries:", largest.len());
+    for Reverse((size, key)) in largest.into_sorted_vec() {
+        let (_, item) = item_name(runtime.as_ref(), &pallet_names, &key);
+        println!("{:>10} bytes  {}  0x{}", size, item, hex::encode(&key));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use crate::block_builder::{BlockConfig, BlockProducer};
+use crate::state_file::load_externalities;
+use crate::state_source::RpcConfig;
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`smoke_test`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct SmokeTestCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to test. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Number of blocks to produce.
+    #[clap(long, default_value_t = 1)]
+    pub blocks: u32,
+
+    #[command(flatten)]
+    pub block_config: BlockConfig,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+pub async fn smoke_test<Block, HostFns>(
+    shared: SharedParams,
+    command: SmokeTestCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let (ext, block_hash) =
+        load_externalities::<Block, HostFns>(&shared, &command.state_path, &command.rpc).await?;
+    let mut producer =
+        BlockProducer::<Block, HostFns>::new(&shared, ext, block_hash, command.block_config)?;
+
+    for _ in 0..command.blocks {
+        let header = producer.produce_block().inspect_err(|e| {
+            log::error!("Failed to produce block: {}", e);
+            log::error!("Use RUST_LOG=runtime=debug to see the runtime logs");
+        })?;
+        log::info!(
+            "Produced block #{} with hash {:?} and state root {:?}",
+            header.number(),
+            header.hash(),
+            header.state_root()
+        );
+    }
+    log::info!("Produced {} blocks successfully", command.blocks);
+
+    Ok(())
+}
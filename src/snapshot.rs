@@ -0,0 +1,357 @@
+//! Write snapshots in the same format as `frame-remote-externalities`, so they can be used with
+//! `try-runtime` and the other subcommands. Snapshots written by older versions of
+//! `frame-remote-externalities` can also be read.
+
+use crate::BlockT;
+use parity_scale_codec::{Compact, Decode, Encode};
+use sp_runtime::traits::{Hash, HashingFor, Header as HeaderT, Zero};
+use sp_runtime::{StateVersion, Storage};
+use sp_state_machine::{Backend, IterArgs, TestExternalities, TrieBackend, TrieBackendBuilder};
+use sp_trie::{HashDBT, MemoryDB, EMPTY_PREFIX};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub type SnapshotVersion = Compact<u16>;
+
+/// Version written by the `frame-remote-externalities` used by `try-runtime`.
+pub const SNAPSHOT_VERSION: SnapshotVersion = Compact(4);
+
+/// Oldest version that can be read. Version 1 stored the key-values instead of the trie nodes.
+pub const MIN_SNAPSHOT_VERSION: u16 = 2;
+
+/// Prefix of the keys of child tries, which are not stored in the top trie.
+const CHILD_STORAGE_KEY_PREFIX: &[u8] = b":child_storage:";
+
+pub type SnapshotBackend<B> = TrieBackend<MemoryDB<HashingFor<B>>, HashingFor<B>>;
+
+/// Copy of the private `Snapshot` struct of `frame-remote-externalities`.
+#[derive(Decode, Encode)]
+pub struct Snapshot<B: BlockT> {
+    pub snapshot_version: SnapshotVersion,
+    pub state_version: StateVersion,
+    // <Vec<Key, (Value, MemoryDbRefCount)>>
+    pub raw_storage: Vec<(Vec<u8>, (Vec<u8>, i32))>,
+    // The storage root of the state. This may vary from the storage root in the header, if not the
+    // entire state was fetched.
+    pub storage_root: B::Hash,
+    pub header: B::Header,
+}
+
+/// Layout of versions 2 and 3, which stored the hash of the block instead of its header. The trie
+/// nodes are keyed by hash in version 2, and by prefixed key in version 3.
+#[derive(Decode, Encode)]
+struct LegacySnapshot<B: BlockT, K> {
+    snapshot_version: SnapshotVersion,
+    state_version: StateVersion,
+    block_hash: B::Hash,
+    raw_storage: Vec<(K, (Vec<u8>, i32))>,
+    storage_root: B::Hash,
+}
+
+/// A snapshot read from a file of any supported version.
+pub struct VersionedSnapshot<B: BlockT> {
+    pub version: u16,
+    pub snapshot: Snapshot<B>,
+    /// Block hash stored by the versions without a header. The header of `snapshot` is then a
+    /// placeholder with only the storage root.
+    pub block_hash: Option<B::Hash>,
+}
+
+fn invalid_data(e: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl<B: BlockT> LegacySnapshot<B, Vec<u8>> {
+    fn into_versioned(self) -> VersionedSnapshot<B> {
+        log::warn!(
+            "Snapshot version {} does not store the block header, only the hash {:?}",
+            self.snapshot_version.0,
+            self.block_hash
+        );
+        let header = B::Header::new(
+            Zero::zero(),
+            Default::default(),
+            self.storage_root,
+            Default::default(),
+            Default::default(),
+        );
+
+        VersionedSnapshot {
+            version: self.snapshot_version.0,
+            snapshot: Snapshot {
+                snapshot_version: SNAPSHOT_VERSION,
+                state_version: self.state_version,
+                raw_storage: self.raw_storage,
+                storage_root: self.storage_root,
+                header,
+            },
+            block_hash: Some(self.block_hash),
+        }
+    }
+}
+
+impl<B: BlockT> Snapshot<B> {
+    /// Build the trie of the top key-values. Child tries are skipped, use [`Self::from_storage`]
+    /// to include them.
+    pub fn from_key_values(
+        top: BTreeMap<Vec<u8>, Vec<u8>>,
+        state_version: StateVersion,
+        header: B::Header,
+    ) -> Self {
+        let storage = Storage {
+            top,
+            children_default: Default::default(),
+        };
+
+        Self::from_storage(storage, state_version, header)
+    }
+
+    /// Build the tries of the top and child key-values. The child storage keys of the top trie
+    /// are recomputed from the child tries, so child tries without key-values are skipped.
+    pub fn from_storage(
+        mut storage: Storage,
+        state_version: StateVersion,
+        header: B::Header,
+    ) -> Self {
+        let count_before = storage.top.len();
+        storage
+            .top
+            .retain(|key, _| !key.starts_with(CHILD_STORAGE_KEY_PREFIX));
+        let count_missing =
+            (count_before - storage.top.len()).saturating_sub(storage.children_default.len());
+        if count_missing > 0 {
+            log::warn!(
+                "Skipped {} child tries without key-values, the storage root will not match",
+                count_missing
+            );
+        }
+
+        let (raw_storage, storage_root) = build_trie::<B>(storage, state_version);
+
+        Self {
+            snapshot_version: SNAPSHOT_VERSION,
+            state_version,
+            raw_storage,
+            storage_root,
+            header,
+        }
+    }
+
+    /// Build the tries of the top and child key-values, with a genesis header for this state.
+    pub fn genesis(storage: Storage, state_version: StateVersion) -> Self {
+        let (raw_storage, storage_root) = build_trie::<B>(storage, state_version);
+        // Same as the genesis block built by the node from a chain spec
+        let extrinsics_root = HashingFor::<B>::trie_root(vec![], state_version);
+        let header = B::Header::new(
+            Zero::zero(),
+            extrinsics_root,
+            storage_root,
+            Default::default(),
+            Default::default(),
+        );
+
+        Self {
+            snapshot_version: SNAPSHOT_VERSION,
+            state_version,
+            raw_storage,
+            storage_root,
+            header,
+        }
+    }
+
+    /// Trie backend with the nodes of the snapshot, at its storage root.
+    pub fn backend(&self) -> SnapshotBackend<B> {
+        let hash_len = self.storage_root.as_ref().len();
+        let mut db = MemoryDB::<HashingFor<B>>::default();
+        for (key, (value, ref_count)) in &self.raw_storage {
+            // The key is the prefix of the node followed by its hash, the prefix is not needed
+            if *ref_count <= 0 || key.len() < hash_len {
+                continue;
+            }
+            let mut hash = B::Hash::default();
+            hash.as_mut().copy_from_slice(&key[key.len() - hash_len..]);
+            db.emplace(hash, EMPTY_PREFIX, value.clone());
+        }
+
+        TrieBackendBuilder::new(db, self.storage_root).build()
+    }
+
+    /// Decode a snapshot of any version from [`MIN_SNAPSHOT_VERSION`] to [`SNAPSHOT_VERSION`].
+    /// Snapshots of older versions are converted to the layout of the current version.
+    pub fn decode_versioned(encoded: &[u8]) -> io::Result<VersionedSnapshot<B>> {
+        let version = SnapshotVersion::decode(&mut &encoded[..])
+            .map_err(invalid_data)?
+            .0;
+        match version {
+            2 => {
+                let legacy = LegacySnapshot::<B, B::Hash>::decode(&mut &encoded[..])
+                    .map_err(invalid_data)?;
+                // The backend only needs the hash at the end of the key
+                let raw_storage = legacy
+                    .raw_storage
+                    .into_iter()
+                    .map(|(hash, node)| (hash.as_ref().to_vec(), node))
+                    .collect();
+                Ok(LegacySnapshot {
+                    snapshot_version: legacy.snapshot_version,
+                    state_version: legacy.state_version,
+                    block_hash: legacy.block_hash,
+                    raw_storage,
+                    storage_root: legacy.storage_root,
+                }
+                .into_versioned())
+            }
+            3 => Ok(LegacySnapshot::<B, Vec<u8>>::decode(&mut &encoded[..])
+                .map_err(invalid_data)?
+                .into_versioned()),
+            4 => Ok(VersionedSnapshot {
+                version,
+                snapshot: Self::decode(&mut &encoded[..]).map_err(invalid_data)?,
+                block_hash: None,
+            }),
+            _ => Err(invalid_data(format!(
+                "Unsupported snapshot version {}, expected {} to {}",
+                version, MIN_SNAPSHOT_VERSION, SNAPSHOT_VERSION.0
+            ))),
+        }
+    }
+
+    /// Encode the snapshot in the layout of `version`. `block_hash` is only stored by the
+    /// versions without a header.
+    pub fn encode_version(&self, version: u16, block_hash: B::Hash) -> io::Result<Vec<u8>> {
+        let snapshot_version = Compact(version);
+        match version {
+            2 => {
+                // Nodes with the same hash under different prefixes are stored once
+                let hash_len = self.storage_root.as_ref().len();
+                let mut nodes = BTreeMap::new();
+                for (key, node) in &self.raw_storage {
+                    if key.len() < hash_len {
+                        continue;
+                    }
+                    let hash =
+                        B::Hash::decode(&mut &key[key.len() - hash_len..]).map_err(invalid_data)?;
+                    nodes.entry(hash).or_insert_with(|| node.clone());
+                }
+                Ok(LegacySnapshot::<B, B::Hash> {
+                    snapshot_version,
+                    state_version: self.state_version,
+                    block_hash,
+                    raw_storage: nodes.into_iter().collect(),
+                    storage_root: self.storage_root,
+                }
+                .encode())
+            }
+            // Same encoding as `LegacySnapshot`, without copying the nodes
+            3 => Ok((
+                snapshot_version,
+                self.state_version,
+                block_hash,
+                &self.raw_storage,
+                self.storage_root,
+            )
+                .encode()),
+            4 => Ok(self.encode()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported snapshot version {}, expected {} to {}",
+                    version, MIN_SNAPSHOT_VERSION, SNAPSHOT_VERSION.0
+                ),
+            )),
+        }
+    }
+
+    pub fn read_versioned(path: &Path) -> io::Result<VersionedSnapshot<B>> {
+        Self::decode_versioned(&fs::read(path)?)
+    }
+
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Ok(Self::read_versioned(path)?.snapshot)
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let encoded = self.encode();
+        log::info!(
+            "Writing snapshot of {} bytes to {}",
+            encoded.len(),
+            path.display()
+        );
+        fs::write(path, encoded)
+    }
+}
+
+fn build_trie<B: BlockT>(
+    storage: Storage,
+    state_version: StateVersion,
+) -> (Vec<(Vec<u8>, (Vec<u8>, i32))>, B::Hash) {
+    TestExternalities::<HashingFor<B>>::new_with_state_version(storage, state_version)
+        .into_raw_snapshot()
+}
+
+/// All the key-values under `prefix`. Fails if some trie node is missing from the backend.
+pub fn prefix_pairs<B: BlockT>(
+    backend: &SnapshotBackend<B>,
+    prefix: &[u8],
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+    let args = IterArgs {
+        prefix: Some(prefix),
+        ..Default::default()
+    };
+    backend.pairs(args)?.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+
+    fn snapshot() -> Snapshot<Block> {
+        let top = (1u8..=5).map(|i| (vec![i], vec![i; 40])).collect();
+        let storage = Storage {
+            top,
+            children_default: Default::default(),
+        };
+
+        Snapshot::genesis(storage, StateVersion::V1)
+    }
+
+    #[test]
+    fn encodes_and_decodes_all_versions() {
+        let snapshot = snapshot();
+        let block_hash = snapshot.header.hash();
+
+        for version in MIN_SNAPSHOT_VERSION..=SNAPSHOT_VERSION.0 {
+            let encoded = snapshot.encode_version(version, block_hash).unwrap();
+            let versioned = Snapshot::<Block>::decode_versioned(&encoded).unwrap();
+
+            assert_eq!(versioned.version, version);
+            assert_eq!(versioned.snapshot.storage_root, snapshot.storage_root);
+            if version < 4 {
+                assert_eq!(versioned.block_hash, Some(block_hash));
+            } else {
+                assert_eq!(versioned.block_hash, None);
+                assert_eq!(versioned.snapshot.header, snapshot.header);
+            }
+            let pairs = prefix_pairs::<Block>(&versioned.snapshot.backend(), &[]).unwrap();
+            assert_eq!(
+                pairs,
+                prefix_pairs::<Block>(&snapshot.backend(), &[]).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        let snapshot = snapshot();
+        let block_hash = snapshot.header.hash();
+
+        assert!(snapshot.encode_version(1, block_hash).is_err());
+        assert!(snapshot.encode_version(5, block_hash).is_err());
+        let mut encoded = snapshot.encode();
+        encoded[..1].copy_from_slice(&Compact(5u16).encode());
+        assert!(Snapshot::<Block>::decode_versioned(&encoded).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! Read the state of a block from the database of a local node, without going through RPC.
+
+use crate::create_snapshot::normalize_prefixes;
+use crate::metadata::{fetch_runtime_version, CODE_KEY};
+use crate::parse;
+use crate::state_file::is_snapshot;
+use crate::state_source::{open_sink, SnapshotSink, StateSink};
+use crate::to_hex_snap::keep_prefixes;
+use crate::BlockT;
+use kvdb_rocksdb::DatabaseConfig;
+use sc_cli::Database;
+use sc_client_api::Backend as _;
+use sc_client_db::{Backend, BlocksPruning, DatabaseSettings, DatabaseSource};
+use sc_executor::HostFunctions;
+use sp_blockchain::HeaderBackend;
+use sp_runtime::app_crypto::sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_runtime::app_crypto::sp_core::storage::ChildInfo;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use sp_state_machine::{Backend as _, IterArgs};
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`snapshot_from_db`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct SnapshotFromDbCommand {
+    /// Path of the node database, for example `<base path>/chains/<chain id>/db/full`. The
+    /// database is opened read-only, so the node can keep running.
+    #[clap(long)]
+    pub db_path: PathBuf,
+
+    /// Database backend of the node. Only RocksDB can be opened read-only.
+    #[arg(long, value_enum, default_value_t = Database::RocksDb)]
+    pub database: Database,
+
+    /// The block hash at which to read the state. Defaults to the latest finalized block.
+    #[arg(long, value_parser = parse::hash, conflicts_with = "number")]
+    pub at: Option<String>,
+
+    /// The block number at which to read the state.
+    #[clap(long)]
+    pub number: Option<u32>,
+
+    /// A pallet to read. Can be provided multiple times. If empty, entire chain state will be
+    /// read.
+    #[arg(short, long, num_args = 1..)]
+    pub pallet: Vec<String>,
+
+    /// Storage entry key prefixes to read. Pass as 0x prefixed hex strings. By default, all keys
+    /// are read.
+    #[arg(long, value_parser = parse::hash, num_args = 1..)]
+    pub prefix: Vec<String>,
+
+    /// Output path. A snapshot is written if it ends in `.snap`, otherwise a hex snapshot.
+    pub output_path: String,
+}
+
+/// Number of columns of the node database, same as `sc-client-db`.
+const NUM_COLUMNS: u32 = 13;
+
+/// Open the RocksDB database as a secondary instance, which never writes to the database files.
+/// The secondary instance keeps its own logs in `secondary_path`. Databases opened this way are
+/// not upgraded by `sc-client-db`.
+fn read_only_source(
+    database: Database,
+    path: &Path,
+    secondary_path: &Path,
+) -> sc_cli::Result<DatabaseSource> {
+    if !matches!(database, Database::RocksDb) {
+        return Err(format!(
+            "Cannot open a {:?} database read-only, only RocksDB is supported",
+            database
+        )
+        .into());
+    }
+    let mut config = DatabaseConfig::with_columns(NUM_COLUMNS);
+    config.secondary = Some(secondary_path.to_path_buf());
+    config.create_if_missing = false;
+    let db = kvdb_rocksdb::Database::open(&config, path).inspect_err(|e| {
+        log::error!("Failed to open database {}: {}", path.display(), e);
+    })?;
+
+    Ok(DatabaseSource::Custom {
+        db: sp_database::as_database(db),
+        require_create_flag: false,
+    })
+}
+
+pub async fn snapshot_from_db<Block, HostFns>(
+    shared: SharedParams,
+    command: SnapshotFromDbCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let secondary_dir = tempfile::tempdir()?;
+    // The state pruning mode is read from the database, blocks are never pruned because nothing
+    // is imported
+    let settings = DatabaseSettings {
+        trie_cache_maximum_size: None,
+        state_pruning: None,
+        source: read_only_source(command.database, &command.db_path, secondary_dir.path())?,
+        blocks_pruning: BlocksPruning::KeepAll,
+        metrics_registry: None,
+    };
+    let backend = Backend::<Block>::new(settings, 0).inspect_err(|e| {
+        log::error!(
+            "Failed to open database {}: {}",
+            command.db_path.display(),
+            e
+        );
+    })?;
+    let blockchain = backend.blockchain();
+
+    let hash = match (&command.at, command.number) {
+        (Some(at), _) => Block::Hash::from_str(&format!("0x{}", at))
+            .map_err(|e| format!("Invalid block hash {}: {:?}", at, e))?,
+        (None, Some(number)) => blockchain
+            .hash(number.into())?
+            .ok_or_else(|| format!("Block #{} not found in database", number))?,
+        (None, None) => blockchain.info().finalized_hash,
+    };
+    let header = blockchain
+        .header(hash)?
+        .ok_or_else(|| format!("Header of block {:?} not found in database", hash))?;
+    log::info!("Reading state at block #{} {:?}", header.number(), hash);
+
+    let state = backend.state_at(hash).inspect_err(|e| {
+        log::error!(
+            "State of block {:?} is not available, it may be pruned",
+            hash
+        );
+        log::error!("{}", e);
+    })?;
+
+    let mut sink: Box<dyn StateSink> = if is_snapshot(&command.output_path) {
+        let state_version = match state.storage(CODE_KEY)? {
+            Some(code) => fetch_runtime_version::<HostFns>(&shared, &code)?.state_version(),
+            None => {
+                return Err("State does not contain :code, cannot find the state version".into())
+            }
+        };
+        let path = PathBuf::from(&command.output_path);
+        Box::new(SnapshotSink::<Block>::new(path, header, state_version))
+    } else {
+        open_sink(&command.output_path, None)?
+    };
+
+    let mut count = 0u64;
+    for prefix in normalize_prefixes(keep_prefixes(command.prefix, command.pallet)) {
+        let args = IterArgs {
+            prefix: Some(&prefix),
+            ..Default::default()
+        };
+        for kv in state.pairs(args)? {
+            let (key, value) = kv?;
+            sink.write(&key, &value)?;
+            count += 1;
+            if count % 100_000 == 0 {
+                log::info!("Read {} keys", count);
+            }
+
+            if let Some(storage_key) = key.strip_prefix(DEFAULT_CHILD_STORAGE_KEY_PREFIX) {
+                let args = IterArgs {
+                    child_info: Some(ChildInfo::new_default(storage_key)),
+                    ..Default::default()
+                };
+                let data = state.pairs(args)?.collect::<Result<_, _>>()?;
+                sink.write_child(&key, data)?;
+            }
+        }
+    }
+    log::info!("Read {} keys", count);
+    sink.finish()?;
+
+    Ok(())
+}
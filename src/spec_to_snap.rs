@@ -0,0 +1,108 @@
+use crate::create_snapshot::parse_hex;
+use crate::metadata::{fetch_runtime_version, runtime_code, CODE_KEY};
+use crate::snapshot::Snapshot;
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use serde_json::{Map, Value};
+use sp_runtime::app_crypto::sp_core::storage::ChildInfo;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use sp_runtime::{Storage, StorageChild};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`spec_to_snap`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct SpecToSnapCommand {
+    /// The raw chain spec to read.
+    #[clap(long)]
+    pub chain_spec_path: String,
+
+    /// The snapshot path to write.
+    pub snapshot_path: PathBuf,
+}
+
+fn parse_storage_map(map: &Map<String, Value>) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, String> {
+    map.iter()
+        .map(|(key, value)| {
+            let value = value
+                .as_str()
+                .ok_or_else(|| format!("Value of key {} is not a string", key))?;
+            Ok((parse_hex(key)?, parse_hex(value)?))
+        })
+        .collect()
+}
+
+/// Read `genesis.raw.top` and `genesis.raw.childrenDefault` of a raw chain spec.
+pub fn read_raw_storage(path: &str) -> Result<Storage, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let spec: Value = serde_json::from_reader(BufReader::new(file))
+        .map_err(|e| format!("Failed to parse chain spec {}: {}", path, e))?;
+
+    let top = spec
+        .pointer("/genesis/raw/top")
+        .and_then(|top| top.as_object())
+        .ok_or("Chain spec has no genesis.raw.top, is it a raw chain spec?")?;
+    let top = parse_storage_map(top)?;
+
+    // The keys of child tries are stored without the `:child_storage:default:` prefix
+    let mut children_default = HashMap::new();
+    if let Some(children) = spec
+        .pointer("/genesis/raw/childrenDefault")
+        .and_then(|children| children.as_object())
+    {
+        for (storage_key, child) in children {
+            let storage_key = parse_hex(storage_key)?;
+            let data = child
+                .as_object()
+                .ok_or_else(|| format!("Child trie {} is not an object", hex::encode(&storage_key)))
+                .and_then(parse_storage_map)?;
+            let child_info = ChildInfo::new_default(&storage_key);
+            children_default.insert(storage_key, StorageChild { data, child_info });
+        }
+    }
+
+    Ok(Storage {
+        top,
+        children_default,
+    })
+}
+
+pub async fn spec_to_snap<Block, HostFns>(
+    shared: SharedParams,
+    command: SpecToSnapCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let storage = read_raw_storage(&command.chain_spec_path)?;
+    log::info!(
+        "Read {} keys and {} child tries",
+        storage.top.len(),
+        storage.children_default.len()
+    );
+
+    let code = runtime_code(&shared, || Ok(storage.top.get(CODE_KEY).cloned()))?;
+    let state_version = fetch_runtime_version::<HostFns>(&shared, &code)?.state_version();
+
+    let snapshot = Snapshot::<Block>::genesis(storage, state_version);
+    log::info!(
+        "Genesis block has hash {:?} and state root {:?}, with state version {:?}",
+        snapshot.header.hash(),
+        snapshot.storage_root,
+        state_version
+    );
+    snapshot.write(&command.snapshot_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,711 @@
+//! Read and rewrite the key-values of hex snapshots and raw chain specs, line by line, without
+//! loading the whole file into memory.
+
+use crate::metadata::{fetch_runtime_version, runtime_code, CODE_KEY};
+use crate::state_source::{open_sink, open_source, RpcConfig, SnapshotSink, StateSink};
+use crate::BlockT;
+use parity_scale_codec::{Codec, Decode};
+use regex::Regex;
+use sc_executor::HostFunctions;
+use sp_runtime::app_crypto::sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_runtime::app_crypto::sp_core::Hasher;
+use sp_runtime::traits::{HashingFor, NumberFor};
+use sp_runtime::StateVersion;
+use sp_state_machine::TestExternalities;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use tempfile::NamedTempFile;
+use try_runtime_core::common::shared_parameters::{Runtime, SharedParams};
+
+/// Matches the start of the `top` object of a raw chain spec, for example:    "top": {
+const TOP_PATTERN: &str = r#""top":\s*\{"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateFileFormat {
+    /// Output of the `to-hex-snap` subcommand
+    HexSnap,
+    /// Chain spec in raw format, only the `top` object is used
+    RawSpec,
+}
+
+/// A chain spec is a JSON object so it starts with `{`, a hex snapshot starts with a key.
+pub fn detect_format(path: &str) -> io::Result<StateFileFormat> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut buf = [0u8; 1];
+    loop {
+        if reader.read(&mut buf)? == 0 {
+            // Empty file, treat it as an empty hex snapshot
+            return Ok(StateFileFormat::HexSnap);
+        }
+        if !buf[0].is_ascii_whitespace() {
+            break;
+        }
+    }
+
+    if buf[0] == b'{' {
+        Ok(StateFileFormat::RawSpec)
+    } else {
+        Ok(StateFileFormat::HexSnap)
+    }
+}
+
+/// Parse a line like `"0x1234": "0xabcd",`
+pub fn parse_kv_line(line: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let line = line.trim().trim_end_matches(',');
+    let (key, value) = line.split_once(':')?;
+    let key = key.trim().strip_prefix("\"0x")?.strip_suffix('"')?;
+    let value = value.trim().strip_prefix("\"0x")?.strip_suffix('"')?;
+
+    Some((hex::decode(key).ok()?, hex::decode(value).ok()?))
+}
+
+/// Format a key-value as `"0x1234": "0xabcd"`, without trailing comma.
+pub fn format_kv(key: &[u8], value: &[u8]) -> String {
+    format!("\"0x{}\": \"0x{}\"", hex::encode(key), hex::encode(value))
+}
+
+/// Tracks whether the current line of a raw chain spec is inside the `top` object.
+struct TopTracker {
+    format: StateFileFormat,
+    top_regex: Regex,
+    seen_top: bool,
+    inside_top: bool,
+}
+
+enum LineKind {
+    /// Line with the start of the `top` object
+    TopStart,
+    /// Line with the end of the `top` object
+    TopEnd,
+    /// Line inside the `top` object, or any line of a hex snapshot
+    Entry,
+    Other,
+}
+
+impl TopTracker {
+    fn new(format: StateFileFormat) -> Self {
+        Self {
+            format,
+            top_regex: Regex::new(TOP_PATTERN).expect("Invalid top regex"),
+            seen_top: false,
+            inside_top: false,
+        }
+    }
+
+    fn classify(&mut self, line: &str) -> LineKind {
+        if self.format == StateFileFormat::HexSnap {
+            return LineKind::Entry;
+        }
+        if self.inside_top {
+            if line.contains('}') {
+                self.inside_top = false;
+                return LineKind::TopEnd;
+            }
+            return LineKind::Entry;
+        }
+        if !self.seen_top && self.top_regex.is_match(line) {
+            self.seen_top = true;
+            // Edge case: `"top": {}` in one line, there is nothing to read
+            self.inside_top = !line.contains('}');
+            return LineKind::TopStart;
+        }
+
+        LineKind::Other
+    }
+}
+
+/// Iterate over the key-values of a hex snapshot or a raw chain spec.
+pub fn key_values(path: &str) -> io::Result<impl Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>>> {
+    let format = detect_format(path)?;
+    let reader = BufReader::new(File::open(path)?);
+    let mut tracker = TopTracker::new(format);
+
+    Ok(reader.lines().filter_map(move |line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        match tracker.classify(&line) {
+            LineKind::Entry => parse_kv_line(&line).map(Ok),
+            _ => None,
+        }
+    }))
+}
+
+/// Find the value of the `:code` key.
+pub fn find_code(path: &str) -> io::Result<Option<Vec<u8>>> {
+    for kv in key_values(path)? {
+        let (key, value) = kv?;
+        if key == CODE_KEY {
+            return Ok(Some(value));
+        }
+    }
+
+    Ok(None)
+}
+
+#[derive(Debug, Default)]
+pub struct RewriteStats {
+    /// Number of key-values written to the output
+    pub written: u64,
+    /// Number of new keys from `insert` that did not exist in the input
+    pub inserted: u64,
+}
+
+/// Writes key-values to the output, taking care of the commas. Raw chain specs cannot have a
+/// trailing comma after the last key of `top`, so keep the last line until we know if it is the
+/// last one.
+struct EntryWriter {
+    format: StateFileFormat,
+    pending: Option<String>,
+    written: u64,
+}
+
+impl EntryWriter {
+    fn write<W: Write>(&mut self, writer: &mut W, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let line = format_kv(key, value);
+        self.written += 1;
+        match self.format {
+            StateFileFormat::HexSnap => writeln!(writer, "{},", line),
+            StateFileFormat::RawSpec => {
+                if let Some(prev) = self.pending.replace(line) {
+                    writeln!(writer, "{},", prev)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn finish<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        if let Some(prev) = self.pending.take() {
+            writeln!(writer, "{}", prev)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Rewrite the key-values of a hex snapshot or a raw chain spec. Every key-value is passed to
+/// `f`, which can return a different key-value or `None` to remove it. Then, keys that exist in
+/// `insert` get their value replaced, and the keys from `insert` that were not found are added
+/// before the first greater key, so the output stays sorted if the input is sorted and `f` does
+/// not change the order of the keys.
+///
+/// The output is written to a temporary file first, so `output_path` can be the same as
+/// `input_path`.
+pub fn rewrite_key_values<F>(
+    input_path: &str,
+    output_path: &str,
+    mut insert: BTreeMap<Vec<u8>, Vec<u8>>,
+    mut f: F,
+) -> io::Result<RewriteStats>
+where
+    F: FnMut(Vec<u8>, Vec<u8>) -> Option<(Vec<u8>, Vec<u8>)>,
+{
+    let format = detect_format(input_path)?;
+    let mut temp = NamedTempFile::new_in(parent_dir(output_path))?;
+    let reader = BufReader::new(File::open(input_path).inspect_err(|e| {
+        log::error!("Failed to open input file: {}", e);
+    })?);
+    let mut tracker = TopTracker::new(format);
+    let mut entries = EntryWriter {
+        format,
+        pending: None,
+        written: 0,
+    };
+    let mut inserted = 0u64;
+    {
+        let mut writer = BufWriter::new(&mut temp);
+        for line in reader.lines() {
+            let line = line?;
+            match tracker.classify(&line) {
+                LineKind::Entry => {
+                    let Some((key, value)) = parse_kv_line(&line) else {
+                        if !line.trim().is_empty() {
+                            log::warn!("Ignoring invalid line: {}", line);
+                        }
+                        continue;
+                    };
+                    if let Some((key, value)) = f(key, value) {
+                        while let Some(entry) = insert.first_entry() {
+                            if *entry.key() >= key {
+                                break;
+                            }
+                            let (insert_key, insert_value) = entry.remove_entry();
+                            entries.write(&mut writer, &insert_key, &insert_value)?;
+                            inserted += 1;
+                        }
+                        let value = insert.remove(&key).unwrap_or(value);
+                        entries.write(&mut writer, &key, &value)?;
+                    }
+                }
+                LineKind::TopEnd => {
+                    for (key, value) in std::mem::take(&mut insert) {
+                        entries.write(&mut writer, &key, &value)?;
+                        inserted += 1;
+                    }
+                    entries.finish(&mut writer)?;
+                    writeln!(writer, "{}", line)?;
+                }
+                LineKind::TopStart | LineKind::Other => {
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+        }
+
+        if format == StateFileFormat::HexSnap {
+            for (key, value) in std::mem::take(&mut insert) {
+                entries.write(&mut writer, &key, &value)?;
+                inserted += 1;
+            }
+        }
+        if !insert.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Could not find \"top\" object in chain spec",
+            ));
+        }
+        writer.flush()?;
+    }
+
+    temp.persist(output_path).map_err(|e| e.error)?;
+
+    Ok(RewriteStats {
+        written: entries.written,
+        inserted,
+    })
+}
+
+/// Key-values of the source of [`merge_key_values`], read one at a time.
+struct MergeSource<I> {
+    key_values: I,
+    next: Option<(Vec<u8>, Vec<u8>)>,
+    last_key: Option<Vec<u8>>,
+    written: u64,
+}
+
+impl<I> MergeSource<I>
+where
+    I: Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>>,
+{
+    fn peek_key(&mut self) -> io::Result<Option<&[u8]>> {
+        if self.next.is_none() {
+            self.next = self.key_values.next().transpose()?;
+            if let (Some((key, _)), Some(last_key)) = (&self.next, &self.last_key) {
+                if key <= last_key {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Keys to merge are not sorted, 0x{} is after 0x{}",
+                            hex::encode(key),
+                            hex::encode(last_key)
+                        ),
+                    ));
+                }
+            }
+        }
+
+        Ok(self.next.as_ref().map(|(key, _)| key.as_slice()))
+    }
+
+    /// Write the key-values before `key`, or all of them if there is no `key`. Returns true if
+    /// the next key-value has `key`, which is then written too.
+    fn write_until<W: Write>(
+        &mut self,
+        writer: &mut W,
+        entries: &mut EntryWriter,
+        key: Option<&[u8]>,
+    ) -> io::Result<bool> {
+        while let Some(next_key) = self.peek_key()? {
+            let found = key == Some(next_key);
+            if key.is_some_and(|key| next_key > key) {
+                break;
+            }
+            let (next_key, value) = self.next.take().expect("Peeked above");
+            entries.write(writer, &next_key, &value)?;
+            self.last_key = Some(next_key);
+            self.written += 1;
+            if found {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Replace keys of a raw chain spec with the key-values of `source`, which must be sorted. The
+/// keys of the chain spec for which `remove` returns true are removed, and `source` is merged
+/// into the `top` object as it is read, so it is never loaded into memory and the output stays
+/// sorted. Keys in both take the value from `source`.
+///
+/// The output is written to a temporary file first, so `output_path` can be the same as
+/// `chain_spec_path`.
+pub fn merge_key_values<R, I>(
+    chain_spec_path: &str,
+    output_path: &str,
+    mut remove: R,
+    source: I,
+) -> io::Result<RewriteStats>
+where
+    R: FnMut(&[u8]) -> bool,
+    I: Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>>,
+{
+    let format = detect_format(chain_spec_path)?;
+    if format != StateFileFormat::RawSpec {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a raw chain spec", chain_spec_path),
+        ));
+    }
+    let mut temp = NamedTempFile::new_in(parent_dir(output_path))?;
+    let reader = BufReader::new(File::open(chain_spec_path).inspect_err(|e| {
+        log::error!("Failed to open chain spec file: {}", e);
+    })?);
+    let mut tracker = TopTracker::new(format);
+    let mut entries = EntryWriter {
+        format,
+        pending: None,
+        written: 0,
+    };
+    let mut source = MergeSource {
+        key_values: source,
+        next: None,
+        last_key: None,
+        written: 0,
+    };
+    let mut seen_top = false;
+    {
+        let mut writer = BufWriter::new(&mut temp);
+        for line in reader.lines() {
+            let line = line?;
+            match tracker.classify(&line) {
+                LineKind::TopStart => {
+                    seen_top = true;
+                    if tracker.inside_top {
+                        writeln!(writer, "{}", line)?;
+                    } else {
+                        // Edge case: `"top": {}` in one line, split it to write the source
+                        let top = tracker.top_regex.find(&line).expect("Matched top");
+                        let (start, end) = line.split_at(top.end());
+                        writeln!(writer, "{}", start)?;
+                        source.write_until(&mut writer, &mut entries, None)?;
+                        entries.finish(&mut writer)?;
+                        writeln!(writer, "{}", end.trim_start())?;
+                    }
+                }
+                LineKind::Entry => {
+                    let Some((key, value)) = parse_kv_line(&line) else {
+                        if !line.trim().is_empty() {
+                            log::warn!("Ignoring invalid line: {}", line);
+                        }
+                        continue;
+                    };
+                    if remove(&key) {
+                        continue;
+                    }
+                    if !source.write_until(&mut writer, &mut entries, Some(&key))? {
+                        entries.write(&mut writer, &key, &value)?;
+                    }
+                }
+                LineKind::TopEnd => {
+                    source.write_until(&mut writer, &mut entries, None)?;
+                    entries.finish(&mut writer)?;
+                    writeln!(writer, "{}", line)?;
+                }
+                LineKind::Other => {
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+        }
+        if !seen_top {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Could not find \"top\" object in chain spec",
+            ));
+        }
+        writer.flush()?;
+    }
+
+    temp.persist(output_path).map_err(|e| e.error)?;
+
+    Ok(RewriteStats {
+        written: entries.written,
+        inserted: source.written,
+    })
+}
+
+/// Directory of this file, to create temporary files next to it.
+pub fn parent_dir(path: &str) -> &Path {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Snapshots are read as tries, other files are read line by line.
+pub fn is_snapshot(path: &str) -> bool {
+    path.ends_with(".snap")
+}
+
+/// Load a snapshot, hex snapshot or raw chain spec as externalities, ready to execute the runtime,
+/// and the hash of its block if known. If `--runtime` is a path, that runtime replaces `:code`.
+pub async fn load_externalities<Block, HostFns>(
+    shared: &SharedParams,
+    path: &str,
+    rpc: &RpcConfig,
+) -> sc_cli::Result<(TestExternalities<HashingFor<Block>>, Option<Block::Hash>)>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let source = open_source::<Block>(path, rpc).await?;
+    let code = runtime_code(shared, || source.code())?;
+    let version = fetch_runtime_version::<HostFns>(shared, &code)?;
+    log::info!(
+        "Using runtime {} version {}",
+        version.spec_name,
+        version.spec_version
+    );
+
+    let block_hash = source
+        .block_hash()
+        .map(|hash| Block::Hash::decode(&mut &hash[..]))
+        .transpose()?;
+    let mut ext =
+        TestExternalities::new_with_state_version(source.into_storage()?, version.state_version());
+    if let Runtime::Path(_) = shared.runtime {
+        ext.insert(CODE_KEY.to_vec(), code);
+        ext.commit_all()?;
+    }
+
+    Ok((ext, block_hash))
+}
+
+/// Read all the key-values of the top trie of some externalities, and of each child trie by its
+/// key in the top trie.
+pub fn externalities_key_values<H>(ext: &mut TestExternalities<H>) -> StateKeyValues
+where
+    H: Hasher + 'static,
+    H::Out: Ord + Codec + 'static,
+{
+    ext.execute_with(|| {
+        let mut top = BTreeMap::new();
+        let mut children = BTreeMap::new();
+        let mut prefix = vec![];
+        while let Some(key) = sp_io::storage::next_key(&prefix) {
+            let value = sp_io::storage::get(&key).unwrap().to_vec();
+            if let Some(storage_key) = key.strip_prefix(DEFAULT_CHILD_STORAGE_KEY_PREFIX) {
+                let mut data = BTreeMap::new();
+                let mut child_prefix = vec![];
+                while let Some(child_key) =
+                    sp_io::default_child_storage::next_key(storage_key, &child_prefix)
+                {
+                    let value = sp_io::default_child_storage::get(storage_key, &child_key)
+                        .unwrap()
+                        .to_vec();
+                    child_prefix = child_key.clone();
+                    data.insert(child_key, value);
+                }
+                children.insert(key.clone(), data);
+            }
+            prefix = key.clone();
+            top.insert(key, value);
+        }
+
+        StateKeyValues { top, children }
+    })
+}
+
+/// Key-values of a state, as read by [`externalities_key_values`].
+pub struct StateKeyValues {
+    pub top: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Child tries by their key in `top`
+    pub children: BTreeMap<Vec<u8>, BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+/// Write the key-values into a raw chain spec, replacing all of its keys, or into a snapshot of
+/// the block with `header` if the output path ends in `.snap`, or as a hex snapshot. Only
+/// snapshots can store child tries.
+pub fn write_state<Block: BlockT>(
+    key_values: StateKeyValues,
+    header: Block::Header,
+    state_version: StateVersion,
+    chain_spec_path: Option<&str>,
+    output_path: &str,
+) -> io::Result<()> {
+    let mut sink: Box<dyn StateSink> = match chain_spec_path {
+        None if is_snapshot(output_path) => Box::new(SnapshotSink::<Block>::new(
+            output_path.into(),
+            header,
+            state_version,
+        )),
+        _ if !key_values.children.is_empty() => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "The state has {} child tries, which can only be written to a snapshot, use \
+                     an output path ending in .snap",
+                    key_values.children.len()
+                ),
+            ))
+        }
+        _ => open_sink(output_path, chain_spec_path)?,
+    };
+    let mut children = key_values.children;
+    for (key, value) in &key_values.top {
+        sink.write(key, value)?;
+        if let Some(data) = children.remove(key) {
+            sink.write_child(key, data)?;
+        }
+    }
+
+    sink.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+
+    #[test]
+    fn formats_and_parses_key_values() {
+        let line = format_kv(&[0x12, 0x34], &[0xab]);
+        assert_eq!(line, r#""0x1234": "0xab""#);
+        assert_eq!(
+            parse_kv_line(&format!("    {},", line)),
+            Some((vec![0x12, 0x34], vec![0xab]))
+        );
+        assert_eq!(parse_kv_line(r#""0x": "0x""#), Some((vec![], vec![])));
+    }
+
+    #[test]
+    fn skips_lines_without_key_values() {
+        assert_eq!(parse_kv_line(r#""top": {"#), None);
+        assert_eq!(parse_kv_line(r#""name": "Dancebox","#), None);
+        assert_eq!(parse_kv_line(r#""0x12": "0xzz""#), None);
+        assert_eq!(parse_kv_line("}"), None);
+    }
+
+    #[test]
+    fn reads_only_top_of_raw_spec() {
+        let mut file = NamedTempFile::new().unwrap();
+        let spec = r#"{
+  "name": "Test",
+  "genesis": {
+    "raw": {
+      "top": {
+        "0x01": "0x0a",
+        "0x02": "0x0b"
+      },
+      "childrenDefault": {
+        "0x03": {
+          "0x04": "0x0c"
+        }
+      }
+    }
+  }
+}"#;
+        file.write_all(spec.as_bytes()).unwrap();
+        let path = file.path().to_str().unwrap();
+
+        assert_eq!(detect_format(path).unwrap(), StateFileFormat::RawSpec);
+        let kvs: Vec<_> = key_values(path).unwrap().map(Result::unwrap).collect();
+        assert_eq!(kvs, vec![(vec![1], vec![0x0a]), (vec![2], vec![0x0b])]);
+    }
+
+    fn write_spec(top: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        let spec = format!(
+            "{{\n  \"genesis\": {{\n    \"raw\": {{\n      {}\n    }}\n  }}\n}}\n",
+            top
+        );
+        file.write_all(spec.as_bytes()).unwrap();
+        file
+    }
+
+    fn merge(spec: &NamedTempFile, source: Vec<(Vec<u8>, Vec<u8>)>) -> (NamedTempFile, u64) {
+        let output = NamedTempFile::new().unwrap();
+        let stats = merge_key_values(
+            spec.path().to_str().unwrap(),
+            output.path().to_str().unwrap(),
+            |key| key.starts_with(b":"),
+            source.into_iter().map(Ok),
+        )
+        .unwrap();
+        (output, stats.inserted)
+    }
+
+    #[tokio::test]
+    async fn merges_sorted_key_values() {
+        let spec = write_spec(
+            r#""top": {
+        "0x01": "0x0a",
+        "0x3a636f6465": "0x00",
+        "0x50": "0x0b"
+      }"#,
+        );
+        let source = vec![
+            (vec![0x02], vec![0x0c]),
+            (CODE_KEY.to_vec(), vec![0xc0, 0xde]),
+            (vec![0x50], vec![0x0d]),
+            (vec![0x60], vec![0x0e]),
+        ];
+        let (output, inserted) = merge(&spec, source);
+        let path = output.path().to_str().unwrap();
+
+        assert_eq!(inserted, 4);
+        let rpc = RpcConfig {
+            batch_size: 1000,
+            retries: 1,
+        };
+        let source = open_source::<Block>(path, &rpc).await.unwrap();
+        assert_eq!(source.get(CODE_KEY).unwrap(), Some(vec![0xc0, 0xde]));
+        let kvs: Vec<_> = source.key_values().unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            kvs,
+            vec![
+                (vec![0x01], vec![0x0a]),
+                (vec![0x02], vec![0x0c]),
+                (CODE_KEY.to_vec(), vec![0xc0, 0xde]),
+                (vec![0x50], vec![0x0d]),
+                (vec![0x60], vec![0x0e]),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_into_empty_top() {
+        let spec = write_spec(r#""top": {}"#);
+        let (output, _) = merge(&spec, vec![(CODE_KEY.to_vec(), vec![1])]);
+
+        let path = output.path().to_str().unwrap();
+        let spec: serde_json::Value = serde_json::from_reader(File::open(path).unwrap()).unwrap();
+        assert_eq!(spec["genesis"]["raw"]["top"]["0x3a636f6465"], "0x01");
+    }
+
+    #[test]
+    fn fails_to_merge_unsorted_key_values() {
+        let spec = write_spec(r#""top": {}"#);
+        let output = NamedTempFile::new().unwrap();
+        let source = vec![(vec![2], vec![]), (vec![1], vec![])];
+        let result = merge_key_values(
+            spec.path().to_str().unwrap(),
+            output.path().to_str().unwrap(),
+            |_| false,
+            source.into_iter().map(Ok),
+        );
+
+        assert!(result.unwrap_err().to_string().contains("not sorted"));
+    }
+}
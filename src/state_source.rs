@@ -0,0 +1,811 @@
+//! Read and write state independently of where it is stored. A [`StateSource`] returns sorted
+//! key-values and can seek to a prefix, a [`StateSink`] receives sorted key-values.
+
+use crate::create_snapshot::{hex_param, normalize_prefixes, parse_hex, Rpc};
+use crate::metadata::CODE_KEY;
+use crate::snapshot::{Snapshot, SnapshotBackend};
+use crate::spec_to_snap::read_raw_storage;
+use crate::state_file::{
+    detect_format, format_kv, is_snapshot, key_values, rewrite_key_values, RewriteStats,
+    StateFileFormat,
+};
+use crate::BlockT;
+use jsonrpsee::rpc_params;
+use serde::de::DeserializeOwned;
+use sp_runtime::app_crypto::sp_core::storage::well_known_keys::DEFAULT_CHILD_STORAGE_KEY_PREFIX;
+use sp_runtime::app_crypto::sp_core::storage::ChildInfo;
+use sp_runtime::traits::Header as HeaderT;
+use sp_runtime::{StateVersion, Storage, StorageChild};
+use sp_state_machine::{Backend, IterArgs};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+pub type KeyValues<'a> = Box<dyn Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Storage key of the child trie whose root is stored at `child_key` in the top trie.
+fn child_storage_key(child_key: &[u8]) -> io::Result<&[u8]> {
+    child_key
+        .strip_prefix(DEFAULT_CHILD_STORAGE_KEY_PREFIX)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid child storage key {}", hex_param(child_key)),
+            )
+        })
+}
+
+/// Key-values of a state, in key order.
+pub trait StateSource {
+    /// Iterate over the key-values that start with `prefix`.
+    fn key_values_with_prefix(&self, prefix: &[u8]) -> io::Result<KeyValues<'_>>;
+
+    /// Iterate over all the key-values.
+    fn key_values(&self) -> io::Result<KeyValues<'_>> {
+        self.key_values_with_prefix(&[])
+    }
+
+    /// Iterate over the key-values that start with any of the `prefixes`, in key order. An empty
+    /// list of prefixes means all the keys.
+    fn key_values_with_prefixes(&self, prefixes: Vec<Vec<u8>>) -> KeyValues<'_> {
+        Box::new(
+            normalize_prefixes(prefixes)
+                .into_iter()
+                .flat_map(move |prefix| match self.key_values_with_prefix(&prefix) {
+                    Ok(key_values) => key_values,
+                    Err(e) => Box::new(iter::once(Err(e))),
+                }),
+        )
+    }
+
+    /// Find the value of a single key.
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match self.key_values_with_prefix(key)?.next() {
+            Some(Ok((k, v))) if k == key => Ok(Some(v)),
+            Some(Err(e)) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    /// Find the value of the `:code` key.
+    fn code(&self) -> io::Result<Option<Vec<u8>>> {
+        self.get(CODE_KEY)
+    }
+
+    /// Hash of the block of the state, if known.
+    fn block_hash(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Key-values of the child trie whose root is stored at `child_key`, a key of the top trie
+    /// that starts with `:child_storage:default:`.
+    fn child_key_values(&self, child_key: &[u8]) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Cannot read child trie {}", hex_param(child_key)),
+        ))
+    }
+
+    /// Load all the key-values into memory, including the child tries of the child storage keys.
+    fn into_storage(self: Box<Self>) -> io::Result<Storage> {
+        let mut storage = Storage::default();
+        for kv in self.key_values()? {
+            let (key, value) = kv?;
+            // The value of a child storage key is the root of the child trie, which is recomputed
+            let Some(storage_key) = key.strip_prefix(DEFAULT_CHILD_STORAGE_KEY_PREFIX) else {
+                storage.top.insert(key, value);
+                continue;
+            };
+            let data = self.child_key_values(&key)?;
+            let child_info = ChildInfo::new_default(storage_key);
+            storage
+                .children_default
+                .insert(storage_key.to_vec(), StorageChild { data, child_info });
+        }
+
+        Ok(storage)
+    }
+}
+
+/// Key-values kept in memory.
+pub struct MemorySource(pub BTreeMap<Vec<u8>, Vec<u8>>);
+
+impl StateSource for MemorySource {
+    fn key_values_with_prefix(&self, prefix: &[u8]) -> io::Result<KeyValues<'_>> {
+        let prefix = prefix.to_vec();
+        Ok(Box::new(
+            self.0
+                .range(prefix.clone()..)
+                .take_while(move |(key, _)| key.starts_with(&prefix))
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        ))
+    }
+
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.0.get(key).cloned())
+    }
+
+    fn into_storage(self: Box<Self>) -> io::Result<Storage> {
+        Ok(Storage {
+            top: self.0,
+            children_default: Default::default(),
+        })
+    }
+}
+
+/// Hex snapshot or raw chain spec, read line by line. Reading key-values in order needs the keys
+/// of the file to be sorted, which is checked while reading.
+pub struct LineFileSource {
+    path: String,
+    format: StateFileFormat,
+}
+
+impl LineFileSource {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_string(),
+            format: detect_format(path)?,
+        })
+    }
+}
+
+impl StateSource for LineFileSource {
+    fn key_values_with_prefix(&self, prefix: &[u8]) -> io::Result<KeyValues<'_>> {
+        Ok(self.key_values_with_prefixes(vec![prefix.to_vec()]))
+    }
+
+    /// Read the file once for all the prefixes, until the last one.
+    fn key_values_with_prefixes(&self, prefixes: Vec<Vec<u8>>) -> KeyValues<'_> {
+        let mut key_values = match key_values(&self.path) {
+            Ok(key_values) => key_values,
+            Err(e) => return Box::new(iter::once(Err(e))),
+        };
+        let mut prefixes = normalize_prefixes(prefixes).into_iter().peekable();
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut done = false;
+        Box::new(iter::from_fn(move || {
+            while !done {
+                let (key, value) = match key_values.next()? {
+                    Ok(kv) => kv,
+                    Err(e) => {
+                        done = true;
+                        return Some(Err(e));
+                    }
+                };
+                if let Some(last_key) = last_key.as_ref().filter(|last_key| **last_key >= key) {
+                    done = true;
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Keys of {} are not sorted, 0x{} is after 0x{}",
+                            self.path,
+                            hex::encode(&key),
+                            hex::encode(last_key)
+                        ),
+                    )));
+                }
+                while prefixes
+                    .peek()
+                    .is_some_and(|prefix| *prefix < key && !key.starts_with(prefix))
+                {
+                    prefixes.next();
+                }
+                let Some(prefix) = prefixes.peek() else {
+                    // All the prefixes are before this key
+                    done = true;
+                    break;
+                };
+                let matches = key.starts_with(prefix);
+                last_key = Some(key.clone());
+                if matches {
+                    return Some(Ok((key, value)));
+                }
+            }
+
+            None
+        }))
+    }
+
+    /// Does not need the keys to be sorted. Raw chain specs also include their child tries.
+    fn into_storage(self: Box<Self>) -> io::Result<Storage> {
+        match self.format {
+            StateFileFormat::HexSnap => Ok(Storage {
+                top: key_values(&self.path)?.collect::<io::Result<_>>()?,
+                children_default: Default::default(),
+            }),
+            StateFileFormat::RawSpec => read_raw_storage(&self.path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// Snapshot in the format of `try-runtime`, including its child tries.
+pub struct SnapshotSource<Block: BlockT> {
+    pub header: Block::Header,
+    pub state_version: StateVersion,
+    backend: SnapshotBackend<Block>,
+}
+
+impl<Block: BlockT> SnapshotSource<Block> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let snapshot = Snapshot::<Block>::read(path)?;
+        let backend = snapshot.backend();
+
+        Ok(Self {
+            header: snapshot.header,
+            state_version: snapshot.state_version,
+            backend,
+        })
+    }
+
+    fn pairs(&self, args: IterArgs) -> io::Result<KeyValues<'_>> {
+        let pairs = self.backend.pairs(args).map_err(io::Error::other)?;
+        Ok(Box::new(pairs.map(|kv| kv.map_err(io::Error::other))))
+    }
+}
+
+impl<Block: BlockT> StateSource for SnapshotSource<Block> {
+    fn key_values_with_prefix(&self, prefix: &[u8]) -> io::Result<KeyValues<'_>> {
+        self.pairs(IterArgs {
+            prefix: Some(prefix),
+            ..Default::default()
+        })
+    }
+
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.backend.storage(key).map_err(io::Error::other)
+    }
+
+    fn block_hash(&self) -> Option<Vec<u8>> {
+        Some(self.header.hash().as_ref().to_vec())
+    }
+
+    fn child_key_values(&self, child_key: &[u8]) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        self.pairs(IterArgs {
+            child_info: Some(ChildInfo::new_default(child_storage_key(child_key)?)),
+            ..Default::default()
+        })?
+        .collect()
+    }
+}
+
+/// State of a live chain at some block, downloaded page by page as it is read.
+pub struct RpcSource {
+    rpc: Rpc,
+    at: String,
+    batch_size: u32,
+}
+
+/// Wait for an RPC request from a sync context. Needs the multi-threaded tokio runtime.
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(future))
+}
+
+impl RpcSource {
+    /// Defaults to the latest finalized block if `at` is not set.
+    pub async fn new(
+        uri: &str,
+        at: Option<String>,
+        batch_size: u32,
+        retries: u32,
+    ) -> Result<Self, String> {
+        let rpc = Rpc::new(uri, retries)?;
+        let at = match at {
+            Some(at) => format!("0x{}", at.trim_start_matches("0x")),
+            None => rpc.finalized_head().await?,
+        };
+        log::info!("Reading state of {} at block {}", uri, at);
+
+        Ok(Self {
+            rpc,
+            at,
+            batch_size,
+        })
+    }
+}
+
+impl StateSource for RpcSource {
+    fn key_values_with_prefix(&self, prefix: &[u8]) -> io::Result<KeyValues<'_>> {
+        let prefix = prefix.to_vec();
+        let mut page = vec![].into_iter();
+        let mut start_key = None;
+        let mut done = false;
+        Ok(Box::new(iter::from_fn(move || loop {
+            if let Some(kv) = page.next() {
+                return Some(Ok(kv));
+            }
+            if done {
+                return None;
+            }
+            let result = block_on(self.rpc.fetch_page(
+                &prefix,
+                start_key.as_deref(),
+                self.batch_size,
+                &self.at,
+            ));
+            match result {
+                Ok((key_values, next_start_key)) => {
+                    done = next_start_key.is_none();
+                    start_key = next_start_key;
+                    page = key_values.into_iter();
+                }
+                Err(e) => {
+                    done = true;
+                    return Some(Err(io::Error::other(e)));
+                }
+            }
+        })))
+    }
+
+    fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let value: Option<String> = block_on(
+            self.rpc
+                .request("state_getStorage", rpc_params![hex_param(key), &self.at]),
+        )
+        .map_err(io::Error::other)?;
+        value
+            .map(|value| parse_hex(&value))
+            .transpose()
+            .map_err(io::Error::other)
+    }
+
+    fn block_hash(&self) -> Option<Vec<u8>> {
+        parse_hex(&self.at).ok()
+    }
+
+    fn child_key_values(&self, child_key: &[u8]) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+        let storage_key = child_storage_key(child_key)?;
+        let data = block_on(
+            self.rpc
+                .fetch_child_trie(child_key, self.batch_size, &self.at),
+        )
+        .map_err(io::Error::other)?;
+        log::info!(
+            "Downloaded {} keys of child trie {}",
+            data.len(),
+            hex_param(storage_key)
+        );
+
+        Ok(data)
+    }
+}
+
+fn is_rpc_uri(location: &str) -> bool {
+    ["ws://", "wss://", "http://", "https://"]
+        .iter()
+        .any(|scheme| location.starts_with(scheme))
+}
+
+/// How to download the state when it is read from a node.
+#[derive(Debug, Clone, clap::Args)]
+pub struct RpcConfig {
+    /// Number of keys to request at a time, when the state is read from a node.
+    #[clap(long, default_value_t = 1000)]
+    pub batch_size: u32,
+
+    /// Number of times to retry a failed request before giving up.
+    #[clap(long, default_value_t = 12)]
+    pub retries: u32,
+}
+
+/// Open a snapshot, hex snapshot, raw chain spec, or the RPC url of a node at its latest
+/// finalized block. Snapshots must end in `.snap`.
+pub async fn open_source<Block: BlockT>(
+    location: &str,
+    rpc: &RpcConfig,
+) -> sc_cli::Result<Box<dyn StateSource>> {
+    if is_rpc_uri(location) {
+        Ok(Box::new(
+            RpcSource::new(location, None, rpc.batch_size, rpc.retries).await?,
+        ))
+    } else if is_snapshot(location) {
+        let source = SnapshotSource::<Block>::open(Path::new(location)).inspect_err(|e| {
+            log::error!("Failed to read snapshot {}: {}", location, e);
+        })?;
+        Ok(Box::new(source))
+    } else {
+        let source = LineFileSource::open(location).inspect_err(|e| {
+            log::error!("Failed to read {}: {}", location, e);
+        })?;
+        Ok(Box::new(source))
+    }
+}
+
+/// Receives key-values in key order, as returned by a [`StateSource`].
+pub trait StateSink {
+    fn write(&mut self, key: &[u8], value: &[u8]) -> io::Result<()>;
+
+    /// Receive the key-values of the child trie whose root is stored at `child_key`, after
+    /// `child_key` itself was written. Only snapshots can store child tries.
+    fn write_child(
+        &mut self,
+        child_key: &[u8],
+        _data: BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "Found child trie {}, child tries can only be written to a snapshot",
+                hex_param(child_key)
+            ),
+        ))
+    }
+
+    /// Flush the output. Sinks that cannot be written incrementally are written here.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+pub struct HexSnapSink {
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl HexSnapSink {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let file = File::create(path).inspect_err(|e| {
+            log::error!("Failed to create output file: {}", e);
+        })?;
+
+        Ok(Self {
+            writer: BufWriter::new(file),
+            written: 0,
+        })
+    }
+}
+
+impl StateSink for HexSnapSink {
+    fn write(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.written += 1;
+        writeln!(self.writer, "{},", format_kv(key, value))
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.writer.flush()?;
+        log::info!("Wrote {} keys into hex snapshot", self.written);
+
+        Ok(())
+    }
+}
+
+/// Replaces all the keys of a raw chain spec.
+pub struct ChainSpecSink {
+    chain_spec_path: String,
+    output_path: String,
+    key_values: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl StateSink for ChainSpecSink {
+    fn write(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.key_values.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let stats = rewrite_key_values(
+            &self.chain_spec_path,
+            &self.output_path,
+            self.key_values,
+            |_, _| None,
+        )?;
+        log::info!("Wrote {} keys into chain spec", stats.written);
+
+        Ok(())
+    }
+}
+
+/// Builds the trie when finished, so the whole state is kept in memory.
+pub struct SnapshotSink<Block: BlockT> {
+    path: PathBuf,
+    header: Block::Header,
+    state_version: StateVersion,
+    storage: Storage,
+}
+
+impl<Block: BlockT> SnapshotSink<Block> {
+    pub fn new(path: PathBuf, header: Block::Header, state_version: StateVersion) -> Self {
+        Self {
+            path,
+            header,
+            state_version,
+            storage: Storage::default(),
+        }
+    }
+}
+
+impl<Block: BlockT> StateSink for SnapshotSink<Block> {
+    fn write(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.storage.top.insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn write_child(
+        &mut self,
+        child_key: &[u8],
+        data: BTreeMap<Vec<u8>, Vec<u8>>,
+    ) -> io::Result<()> {
+        let storage_key = child_storage_key(child_key)?;
+        let child_info = ChildInfo::new_default(storage_key);
+        self.storage
+            .children_default
+            .insert(storage_key.to_vec(), StorageChild { data, child_info });
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let snapshot =
+            Snapshot::<Block>::from_storage(self.storage, self.state_version, self.header);
+        if snapshot.storage_root != *snapshot.header.state_root() {
+            log::warn!(
+                "Snapshot has storage root {:?} but the header has {:?}, this is expected if only \
+                 part of the state was written",
+                snapshot.storage_root,
+                snapshot.header.state_root()
+            );
+        }
+        snapshot.write(&self.path)
+    }
+}
+
+/// Write into this raw chain spec, replacing all of its keys, or a hex snapshot if there is no
+/// chain spec. Snapshots need a header, so use [`SnapshotSink`] for them.
+pub fn open_sink(
+    output_path: &str,
+    chain_spec_path: Option<&str>,
+) -> io::Result<Box<dyn StateSink>> {
+    match chain_spec_path {
+        Some(chain_spec_path) => Ok(Box::new(ChainSpecSink {
+            chain_spec_path: chain_spec_path.to_string(),
+            output_path: output_path.to_string(),
+            key_values: BTreeMap::new(),
+        })),
+        None if is_snapshot(output_path) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Cannot write a snapshot without a header, write a hex snapshot and use to-snap",
+        )),
+        None => Ok(Box::new(HexSnapSink::create(output_path)?)),
+    }
+}
+
+/// Like [`open_source`], and also return the header and state version of the block of the state,
+/// which are needed to write it as a snapshot. Hex snapshots and raw chain specs have no block.
+pub async fn open_source_with_block<Block>(
+    location: &str,
+    rpc: &RpcConfig,
+) -> sc_cli::Result<(Box<dyn StateSource>, Option<(Block::Header, StateVersion)>)>
+where
+    Block: BlockT,
+    Block::Header: DeserializeOwned,
+{
+    if is_rpc_uri(location) {
+        let source = RpcSource::new(location, None, rpc.batch_size, rpc.retries).await?;
+        let header = source.rpc.header(&source.at).await?;
+        let state_version = source.rpc.state_version(&source.at).await?;
+        Ok((Box::new(source), Some((header, state_version))))
+    } else if is_snapshot(location) {
+        let source = SnapshotSource::<Block>::open(Path::new(location)).inspect_err(|e| {
+            log::error!("Failed to read snapshot {}: {}", location, e);
+        })?;
+        let block = (source.header.clone(), source.state_version);
+        Ok((Box::new(source), Some(block)))
+    } else {
+        Ok((open_source::<Block>(location, rpc).await?, None))
+    }
+}
+
+/// Rewrite the state at `location` into `output_path`, in the same way as
+/// [`rewrite_key_values`]. Hex snapshots and raw chain specs are rewritten line by line. States
+/// with a block are written as a snapshot of that block if `output_path` ends in `.snap`, with
+/// their child tries, and as a hex snapshot otherwise.
+pub fn rewrite_state<Block, F>(
+    location: &str,
+    source: &dyn StateSource,
+    block: Option<(Block::Header, StateVersion)>,
+    output_path: &str,
+    mut insert: BTreeMap<Vec<u8>, Vec<u8>>,
+    mut f: F,
+) -> sc_cli::Result<RewriteStats>
+where
+    Block: BlockT,
+    F: FnMut(Vec<u8>, Vec<u8>) -> Option<(Vec<u8>, Vec<u8>)>,
+{
+    if is_rpc_uri(output_path) {
+        return Err("Cannot write the state into a node, pass --output-path".into());
+    }
+    let mut sink: Box<dyn StateSink> = match block {
+        Some((header, state_version)) if is_snapshot(output_path) => Box::new(
+            SnapshotSink::<Block>::new(output_path.into(), header, state_version),
+        ),
+        Some(_) => open_sink(output_path, None)?,
+        None if is_snapshot(output_path) => {
+            return Err(format!(
+                "{} has no block header to write a snapshot, write a hex snapshot and use to-snap \
+                 or spec-to-snap",
+                location
+            )
+            .into())
+        }
+        None => return Ok(rewrite_key_values(location, output_path, insert, f)?),
+    };
+
+    let mut stats = RewriteStats::default();
+    for kv in source.key_values()? {
+        let (key, value) = kv?;
+        let Some((key, value)) = f(key, value) else {
+            continue;
+        };
+        while let Some(entry) = insert.first_entry() {
+            if *entry.key() >= key {
+                break;
+            }
+            let (insert_key, insert_value) = entry.remove_entry();
+            sink.write(&insert_key, &insert_value)?;
+            stats.written += 1;
+            stats.inserted += 1;
+        }
+        let value = insert.remove(&key).unwrap_or(value);
+        sink.write(&key, &value)?;
+        stats.written += 1;
+        if key.starts_with(DEFAULT_CHILD_STORAGE_KEY_PREFIX) {
+            sink.write_child(&key, source.child_key_values(&key)?)?;
+        }
+    }
+    for (key, value) in insert {
+        sink.write(&key, &value)?;
+        stats.written += 1;
+        stats.inserted += 1;
+    }
+    sink.finish()?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Block;
+    use serde_json::json;
+    use std::fs;
+
+    fn hex_snap(keys: &[&[u8]]) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let lines: Vec<String> = keys
+            .iter()
+            .map(|key| format!("{},\n", format_kv(key, &[1])))
+            .collect();
+        fs::write(file.path(), lines.concat()).unwrap();
+        file
+    }
+
+    fn keys(key_values: KeyValues<'_>) -> Vec<Vec<u8>> {
+        key_values.map(|kv| kv.unwrap().0).collect()
+    }
+
+    #[test]
+    fn reads_prefixes_in_one_pass() {
+        let file = hex_snap(&[&[1], &[1, 1], &[2], &[3, 1], &[3, 2], &[4]]);
+        let source = LineFileSource::open(file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            keys(source.key_values_with_prefixes(vec![vec![3], vec![1, 1], vec![1]])),
+            vec![vec![1], vec![1, 1], vec![3, 1], vec![3, 2]]
+        );
+        assert_eq!(keys(source.key_values().unwrap()).len(), 6);
+        assert_eq!(source.get(&[2]).unwrap(), Some(vec![1]));
+        assert_eq!(source.get(&[5]).unwrap(), None);
+    }
+
+    #[test]
+    fn fails_on_unsorted_keys() {
+        let file = hex_snap(&[&[1], &[3], &[2]]);
+        let source = LineFileSource::open(file.path().to_str().unwrap()).unwrap();
+
+        let result: io::Result<Vec<_>> = source.key_values().unwrap().collect();
+        assert!(result.unwrap_err().to_string().contains("not sorted"));
+        // Stops before the unsorted key
+        assert_eq!(source.get(&[1]).unwrap(), Some(vec![1]));
+        // The order does not matter when loading everything
+        assert_eq!(Box::new(source).into_storage().unwrap().top.len(), 3);
+    }
+
+    #[test]
+    fn loads_child_tries_of_raw_spec() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let spec = json!({
+            "genesis": {
+                "raw": {
+                    "top": {"0x01": "0x02"},
+                    "childrenDefault": {"0x0a": {"0x03": "0x04"}},
+                }
+            }
+        });
+        fs::write(file.path(), serde_json::to_string_pretty(&spec).unwrap()).unwrap();
+        let source = LineFileSource::open(file.path().to_str().unwrap()).unwrap();
+        let storage = Box::new(source).into_storage().unwrap();
+
+        assert_eq!(storage.top, BTreeMap::from([(vec![1], vec![2])]));
+        assert_eq!(
+            storage.children_default[&vec![0x0a]].data,
+            BTreeMap::from([(vec![3], vec![4])])
+        );
+    }
+
+    /// Source with one child trie, like a node.
+    struct ChildSource {
+        top: MemorySource,
+        child: Option<BTreeMap<Vec<u8>, Vec<u8>>>,
+    }
+
+    impl StateSource for ChildSource {
+        fn key_values_with_prefix(&self, prefix: &[u8]) -> io::Result<KeyValues<'_>> {
+            self.top.key_values_with_prefix(prefix)
+        }
+
+        fn child_key_values(&self, child_key: &[u8]) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+            match &self.child {
+                Some(child) => Ok(child.clone()),
+                None => Err(io::Error::other(hex_param(child_key))),
+            }
+        }
+    }
+
+    fn child_source(child: Option<BTreeMap<Vec<u8>, Vec<u8>>>) -> Box<ChildSource> {
+        let child_key = [DEFAULT_CHILD_STORAGE_KEY_PREFIX, b"crowdloan"].concat();
+        let top = BTreeMap::from([(vec![1], vec![2]), (child_key, vec![0; 32])]);
+        Box::new(ChildSource {
+            top: MemorySource(top),
+            child,
+        })
+    }
+
+    #[test]
+    fn loads_child_tries_of_child_storage_keys() {
+        let child = BTreeMap::from([(vec![3], vec![4])]);
+        let storage = child_source(Some(child.clone())).into_storage().unwrap();
+
+        assert_eq!(storage.top, BTreeMap::from([(vec![1], vec![2])]));
+        assert_eq!(storage.children_default[&b"crowdloan".to_vec()].data, child);
+    }
+
+    #[test]
+    fn fails_to_load_unreadable_child_tries() {
+        assert!(child_source(None).into_storage().is_err());
+    }
+
+    #[test]
+    fn rewrites_into_snapshot_with_child_tries() {
+        let child = BTreeMap::from([(vec![3], vec![4])]);
+        let source = child_source(Some(child.clone()));
+        let header = <Block as BlockT>::Header::new(
+            0,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("state.snap");
+        let insert = BTreeMap::from([(vec![0], vec![9])]);
+
+        let stats = rewrite_state::<Block, _>(
+            "memory",
+            source.as_ref(),
+            Some((header, StateVersion::V1)),
+            output_path.to_str().unwrap(),
+            insert,
+            |key, value| (key != [1]).then_some((key, value)),
+        )
+        .unwrap();
+
+        assert_eq!(stats.inserted, 1);
+        let snapshot = SnapshotSource::<Block>::open(&output_path).unwrap();
+        assert_eq!(snapshot.get(&[0]).unwrap(), Some(vec![9]));
+        assert_eq!(snapshot.get(&[1]).unwrap(), None);
+        let child_key = [DEFAULT_CHILD_STORAGE_KEY_PREFIX, b"crowdloan"].concat();
+        assert_eq!(snapshot.child_key_values(&child_key).unwrap(), child);
+    }
+}
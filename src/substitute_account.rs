@@ -0,0 +1,350 @@
+use crate::metadata::{
+    hash_key, hash_len, is_transparent, load_runtime_info, runtime_code, RuntimeInfo, StorageItem,
+};
+use crate::scale_json::substitute;
+use crate::state_source::{open_source_with_block, rewrite_state, RpcConfig};
+use crate::BlockT;
+use sc_executor::HostFunctions;
+use scale_info::form::PortableForm;
+use scale_info::{PortableRegistry, Type, TypeDef};
+use sp_runtime::traits::NumberFor;
+use sp_runtime::AccountId32;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::Debug;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`substitute_account`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct SubstituteAccountCommand {
+    /// Account to replace. Hex or SS58 format.
+    #[arg(long)]
+    pub from: String,
+
+    /// New account. Hex or SS58 format.
+    #[arg(long)]
+    pub to: String,
+
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to modify. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Output path, defaults to input path. A snapshot or a node can also be written as a
+    /// snapshot of its block if the path ends in `.snap`, otherwise as a hex snapshot.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    /// Replace the existing entries of the new account when an entry of the old account is moved
+    /// to the same key, for example `System::Account`. By default this is an error.
+    #[clap(long)]
+    pub overwrite: bool,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+fn parse_account(account: &str) -> Result<Vec<u8>, String> {
+    match account.strip_prefix("0x") {
+        Some(account_hex) => {
+            hex::decode(account_hex).map_err(|e| format!("Invalid hex account {}: {}", account, e))
+        }
+        None => AccountId32::from_str(account)
+            .map(|a| <[u8; 32]>::from(a).to_vec())
+            .map_err(|e| format!("Invalid SS58 account {}: {}", account, e)),
+    }
+}
+
+/// `AccountId32` from `sp_core`, or `AccountId20` used by ethereum compatible chains.
+fn is_account_type(ty: &Type<PortableForm>) -> bool {
+    matches!(
+        ty.path.segments.last().map(|s| s.as_str()),
+        Some("AccountId32" | "AccountId20")
+    )
+}
+
+/// Lengths of the account types of the runtime.
+fn account_lengths(types: &PortableRegistry) -> BTreeSet<usize> {
+    types
+        .types
+        .iter()
+        .filter(|ty| is_account_type(&ty.ty))
+        .filter_map(|ty| match &ty.ty.type_def {
+            TypeDef::Composite(composite) if composite.fields.len() == 1 => {
+                match &types.resolve(composite.fields[0].ty.id)?.type_def {
+                    TypeDef::Array(array) => Some(array.len as usize),
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Number of keys and values rewritten for one storage item.
+#[derive(Debug, Default)]
+struct ItemReport {
+    keys: u64,
+    values: u64,
+    decode_errors: u64,
+}
+
+struct Substitution<'a> {
+    runtime: &'a RuntimeInfo,
+    from: Vec<u8>,
+    to: Vec<u8>,
+    contains_account: HashMap<u32, bool>,
+}
+
+impl Substitution<'_> {
+    /// Returns the rewritten value, or `None` if it does not contain the account.
+    fn value(&mut self, item: StorageItem, value: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut out = Vec::with_capacity(value.len());
+        let mut input = value;
+        let mut changed = false;
+        substitute(
+            &self.runtime.types,
+            item.value_type(),
+            &mut input,
+            &mut out,
+            &is_account_type,
+            &mut |account| {
+                (account == self.from.as_slice()).then(|| {
+                    changed = true;
+                    self.to.clone()
+                })
+            },
+            &mut self.contains_account,
+        )?;
+        // Keep any bytes that are not part of the type
+        out.extend_from_slice(input);
+
+        Ok(changed.then_some(out))
+    }
+
+    /// Returns the rewritten key, or `None` if it does not contain the account. Keys with
+    /// transparent hashers are decoded and hashed again, keys with opaque hashers can only be
+    /// replaced if the whole key is the account.
+    fn key(&mut self, item: StorageItem, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let key_types = item.key_types(&self.runtime.types)?;
+        let mut out = key[..32].to_vec();
+        let mut input = &key[32..];
+        let mut changed = false;
+
+        for (hasher, ty) in item.hashers().iter().zip(key_types) {
+            let len = hash_len(hasher);
+            if input.len() < len {
+                return Err("Key too short".to_string());
+            }
+            let (hash, rest) = input.split_at(len);
+            input = rest;
+
+            if !is_transparent(hasher) {
+                let is_account = self.runtime.types.resolve(ty).is_some_and(is_account_type);
+                if is_account && hash == hash_key(hasher, &self.from) {
+                    out.extend(hash_key(hasher, &self.to));
+                    changed = true;
+                } else {
+                    out.extend_from_slice(hash);
+                }
+                continue;
+            }
+
+            let start = input;
+            let mut encoded = vec![];
+            let mut component_changed = false;
+            substitute(
+                &self.runtime.types,
+                ty,
+                &mut input,
+                &mut encoded,
+                &is_account_type,
+                &mut |account| {
+                    (account == self.from.as_slice()).then(|| {
+                        component_changed = true;
+                        self.to.clone()
+                    })
+                },
+                &mut self.contains_account,
+            )?;
+            if component_changed {
+                out.extend(hash_key(hasher, &encoded));
+                changed = true;
+            } else {
+                out.extend_from_slice(hash);
+                out.extend_from_slice(&start[..start.len() - input.len()]);
+            }
+        }
+        out.extend_from_slice(input);
+
+        Ok(changed.then_some(out))
+    }
+}
+
+pub async fn substitute_account<Block, HostFns>(
+    shared: SharedParams,
+    command: SubstituteAccountCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let from = parse_account(&command.from)?;
+    let to = parse_account(&command.to)?;
+    if from.len() != to.len() {
+        return Err(format!(
+            "Accounts must have the same length, found {} and {} bytes",
+            from.len(),
+            to.len()
+        )
+        .into());
+    }
+
+    let (source, block) =
+        open_source_with_block::<Block>(&command.state_path, &command.rpc).await?;
+    let code = runtime_code(&shared, || source.code())?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &code)?;
+
+    log::info!(
+        "Replacing account 0x{} with 0x{}",
+        hex::encode(&from),
+        hex::encode(&to)
+    );
+
+    let account_lengths = account_lengths(&runtime.types);
+    if !account_lengths.contains(&from.len()) {
+        return Err(format!(
+            "Accounts have {} bytes, but the accounts of this runtime have {:?} bytes",
+            from.len(),
+            account_lengths
+        )
+        .into());
+    }
+
+    let mut substitution = Substitution {
+        runtime: &runtime,
+        from,
+        to,
+        contains_account: HashMap::new(),
+    };
+    let mut report: BTreeMap<String, ItemReport> = BTreeMap::new();
+    let output_path = command
+        .output_path
+        .unwrap_or_else(|| command.state_path.clone());
+
+    // First pass: find the new key-values. Keys that contain the account are removed and their
+    // new key is inserted in order in the second pass, so the output stays sorted.
+    let mut moved_keys = HashSet::new();
+    let mut moved = BTreeMap::new();
+    let mut changed = BTreeMap::new();
+    for kv in source.key_values()? {
+        let (key, value) = kv?;
+        let Some(item) = runtime.storage_item_of_key(&key) else {
+            // Well known keys and keys of unknown pallets
+            continue;
+        };
+
+        let new_key = substitution.key(item, &key);
+        let new_value = substitution.value(item, &value);
+        let item_report = report.entry(item.to_string()).or_default();
+        let new_key = match new_key {
+            Ok(Some(new_key)) => {
+                item_report.keys += 1;
+                Some(new_key)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                log::debug!("Failed to decode key 0x{}: {}", hex::encode(&key), e);
+                item_report.decode_errors += 1;
+                None
+            }
+        };
+        let new_value = match new_value {
+            Ok(Some(new_value)) => {
+                item_report.values += 1;
+                Some(new_value)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                log::debug!("Failed to decode value of 0x{}: {}", hex::encode(&key), e);
+                item_report.decode_errors += 1;
+                None
+            }
+        };
+
+        match (new_key, new_value) {
+            (Some(new_key), new_value) => {
+                moved.insert(new_key, new_value.unwrap_or(value));
+                moved_keys.insert(key);
+            }
+            (None, Some(new_value)) => {
+                changed.insert(key, new_value);
+            }
+            (None, None) => {}
+        }
+    }
+
+    // A moved key may already exist, for example `System::Account` if the new account has a
+    // balance
+    let mut collisions = 0u64;
+    for kv in source.key_values()? {
+        let (key, _) = kv?;
+        if moved.contains_key(&key) && !moved_keys.contains(&key) {
+            log::warn!(
+                "Key 0x{} of the new account already exists",
+                hex::encode(&key)
+            );
+            collisions += 1;
+        }
+    }
+    if collisions > 0 {
+        if !command.overwrite {
+            return Err(format!(
+                "{} keys of the new account already exist, pass --overwrite to replace them",
+                collisions
+            )
+            .into());
+        }
+        log::warn!("Overwriting {} keys of the new account", collisions);
+    }
+
+    // Moved key-values replace the existing ones
+    let mut insert = changed;
+    insert.extend(moved);
+    rewrite_state::<Block, _>(
+        &command.state_path,
+        source.as_ref(),
+        block,
+        &output_path,
+        insert,
+        |key, value| (!moved_keys.contains(&key)).then_some((key, value)),
+    )?;
+
+    let mut touched = 0;
+    for (item, item_report) in &report {
+        if item_report.keys > 0 || item_report.values > 0 {
+            touched += 1;
+            log::info!(
+                "{}: replaced account in {} keys and {} values",
+                item,
+                item_report.keys,
+                item_report.values
+            );
+        }
+        if item_report.decode_errors > 0 {
+            log::warn!(
+                "{}: {} keys or values could not be decoded and were not modified",
+                item,
+                item_report.decode_errors
+            );
+        }
+    }
+    log::info!("Modified {} storage items", touched);
+
+    Ok(())
+}
@@ -1,13 +1,12 @@
 use crate::parse;
 use crate::should_be_public::build_executor;
+use crate::state_source::{open_sink, open_source, RpcConfig};
 use crate::BlockT;
 use frame_remote_externalities::RemoteExternalities;
 use sc_executor::HostFunctions;
 use sp_runtime::app_crypto::sp_core::twox_128;
 use sp_runtime::traits::NumberFor;
 use std::fmt::Debug;
-use std::fs::File;
-use std::io::Write;
 use std::mem;
 use std::str::FromStr;
 use try_runtime_core::common::shared_parameters::SharedParams;
@@ -28,16 +27,20 @@ pub struct ToHexSnapCommand {
     #[arg(long, value_parser = parse::hash, num_args = 1..)]
     pub prefix: Vec<String>,
 
-    /// The snapshot path to read.
+    /// The snapshot path to read. Can also be a hex snapshot, raw chain spec or the RPC url of a
+    /// node, to filter them.
     #[clap(long)]
     pub snapshot_path: String,
 
     #[clap(long)]
     pub output_path: String,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
 }
 
 pub async fn to_hex_snap<Block, HostFns>(
-    shared: SharedParams,
+    _shared: SharedParams,
     command: ToHexSnapCommand,
 ) -> sc_cli::Result<()>
 where
@@ -52,8 +55,32 @@ where
     // Only keep requested pallet storage
     // PooledStaking
     //let pallet_prefix = hex::decode("359e684ff9b0738b7dc97123fd114c24").unwrap();
-    let keep_prefixes = command
-        .prefix
+    let keep_prefixes = keep_prefixes(command.prefix, command.pallet);
+
+    if !keep_prefixes.is_empty() {
+        log::info!(
+            "Will only keep prefixes: {:#?}",
+            keep_prefixes
+                .iter()
+                .map(|x| hex::encode(x))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    let source = open_source::<Block>(&command.snapshot_path, &command.rpc).await?;
+    let mut sink = open_sink(&command.output_path, None)?;
+    for kv in source.key_values_with_prefixes(keep_prefixes) {
+        let (key, value) = kv?;
+        sink.write(&key, &value)?;
+    }
+    sink.finish()?;
+
+    Ok(())
+}
+
+/// Convert the `--prefix` and `--pallet` args into a list of key prefixes.
+pub fn keep_prefixes(prefix: Vec<String>, pallet: Vec<String>) -> Vec<Vec<u8>> {
+    prefix
         .into_iter()
         .map(|x| {
             hex::decode(&x).unwrap_or_else(|_e| {
@@ -64,66 +91,40 @@ where
             })
         })
         .chain(
-            command
-                .pallet
+            pallet
                 .into_iter()
                 .map(|pallet_name| twox_128(pallet_name.as_bytes()).to_vec()),
         )
-        .collect::<Vec<_>>();
-
-    if !keep_prefixes.is_empty() {
-        log::info!(
-            "Will only keep prefixes: {:#?}",
-            keep_prefixes
-                .iter()
-                .map(|x| hex::encode(x))
-                .collect::<Vec<_>>()
-        );
-    }
+        .collect::<Vec<_>>()
+}
 
-    let mut output_file = File::create(command.output_path).inspect_err(|e| {
-        log::error!("Failed to create output file: {}", e);
-    })?;
-
-    let ext = {
-        let filename = command.snapshot_path;
-
-        let state = State::Snap {
-            path: Some(filename.into()),
-        };
-        let executor = build_executor::<HostFns>(&shared);
-        let runtime_checks = RuntimeChecks {
-            name_matches: false,
-            version_increases: false,
-            try_runtime_feature_enabled: false,
-        };
-
-        state
-            .to_ext::<Block, HostFns>(&shared, &executor, None, runtime_checks)
-            .await?
+/// Load a snapshot file into externalities.
+pub async fn load_snapshot<Block, HostFns>(
+    shared: &SharedParams,
+    snapshot_path: String,
+) -> sc_cli::Result<RemoteExternalities<Block>>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let state = State::Snap {
+        path: Some(snapshot_path.into()),
+    };
+    let executor = build_executor::<HostFns>(shared);
+    let runtime_checks = RuntimeChecks {
+        name_matches: false,
+        version_increases: false,
+        try_runtime_feature_enabled: false,
     };
 
-    let mut ext: RemoteExternalities<Block> = ext;
-
-    for (key, value) in storage_iter(&mut ext) {
-        if !keep_prefixes.is_empty()
-            && !keep_prefixes
-                .iter()
-                .any(|pallet_prefix| key.starts_with(pallet_prefix))
-        {
-            // Skip this key as it doesn't match any of the requested prefixes
-            continue;
-        }
-
-        writeln!(
-            output_file,
-            "\"0x{}\": \"0x{}\",",
-            hex::encode(&key),
-            hex::encode(&value)
-        )?;
-    }
-
-    Ok(())
+    state
+        .to_ext::<Block, HostFns>(shared, &executor, None, runtime_checks)
+        .await
 }
 
 /*
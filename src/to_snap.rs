@@ -0,0 +1,110 @@
+use crate::metadata::{fetch_runtime_version, runtime_code, CODE_KEY};
+use crate::snapshot::{prefix_pairs, Snapshot};
+use crate::state_file::{is_snapshot, key_values};
+use crate::to_hex_snap::keep_prefixes;
+use crate::{parse, BlockT};
+use sc_executor::HostFunctions;
+use sp_runtime::traits::{Header as HeaderT, NumberFor};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::{Runtime, SharedParams};
+
+/// Configurations for [`to_snap`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct ToSnapCommand {
+    /// A pallet to keep. Can be provided multiple times. If empty, entire chain state will be
+    /// kept.
+    #[arg(short, long, num_args = 1..)]
+    pub pallet: Vec<String>,
+
+    /// Storage entry key prefixes to keep. Pass as 0x prefixed hex strings. By default, all keys
+    /// are kept.
+    #[arg(long, value_parser = parse::hash, num_args = 1..)]
+    pub prefix: Vec<String>,
+
+    /// The snapshot or hex snapshot to read. Snapshots must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// Snapshot to copy the block header from. Defaults to the input snapshot, required if the
+    /// input is a hex snapshot.
+    #[clap(long)]
+    pub header_from: Option<PathBuf>,
+
+    /// The snapshot path to write.
+    pub snapshot_path: PathBuf,
+}
+
+pub async fn to_snap<Block, HostFns>(
+    shared: SharedParams,
+    command: ToSnapCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let prefixes = keep_prefixes(command.prefix, command.pallet);
+    let keep = |key: &[u8]| prefixes.is_empty() || prefixes.iter().any(|p| key.starts_with(p));
+
+    let (top, source) = if is_snapshot(&command.state_path) {
+        let snapshot = Snapshot::<Block>::read(Path::new(&command.state_path))?;
+        let mut top = BTreeMap::new();
+        for (key, value) in prefix_pairs::<Block>(&snapshot.backend(), &[])? {
+            if keep(&key) {
+                top.insert(key, value);
+            }
+        }
+        (top, Some(snapshot))
+    } else {
+        let mut top = BTreeMap::new();
+        for kv in key_values(&command.state_path)? {
+            let (key, value) = kv?;
+            if keep(&key) {
+                top.insert(key, value);
+            }
+        }
+        (top, None)
+    };
+    log::info!("Keeping {} keys", top.len());
+
+    let header_source = match (&command.header_from, source) {
+        (Some(header_from), _) => Snapshot::<Block>::read(header_from).inspect_err(|e| {
+            log::error!("Failed to read {}: {}", header_from.display(), e);
+        })?,
+        (None, Some(source)) => source,
+        (None, None) => {
+            return Err(
+                "Hex snapshots have no header, pass the original snapshot with --header-from"
+                    .into(),
+            )
+        }
+    };
+
+    // The filtered state may not contain `:code`, then the state version of the original
+    // snapshot is used
+    let state_version = match (&shared.runtime, top.get(CODE_KEY)) {
+        (Runtime::Existing, None) => header_source.state_version,
+        _ => {
+            let code = runtime_code(&shared, || Ok(top.get(CODE_KEY).cloned()))?;
+            fetch_runtime_version::<HostFns>(&shared, &code)?.state_version()
+        }
+    };
+
+    let snapshot = Snapshot::<Block>::from_key_values(top, state_version, header_source.header);
+    if snapshot.storage_root != *snapshot.header.state_root() {
+        log::info!(
+            "Storage root {:?} differs from the header, as expected for a partial state",
+            snapshot.storage_root
+        );
+    }
+    snapshot.write(&command.snapshot_path)?;
+
+    Ok(())
+}
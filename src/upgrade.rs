@@ -0,0 +1,158 @@
+use crate::block_builder::{BlockConfig, BlockProducer};
+use crate::metadata::{
+    fetch_runtime_version, load_runtime_info, RuntimeInfo, StorageItem, CODE_KEY,
+};
+use crate::state_file::{externalities_key_values, write_state};
+use crate::state_source::{open_source, RpcConfig};
+use crate::BlockT;
+use parity_scale_codec::Decode;
+use sc_executor::HostFunctions;
+use sp_runtime::traits::NumberFor;
+use sp_state_machine::TestExternalities;
+use std::fmt::Debug;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use try_runtime_core::common::shared_parameters::SharedParams;
+
+/// Configurations for [`upgrade`].
+#[derive(Debug, Clone, clap::Parser)]
+pub struct UpgradeCommand {
+    /// The snapshot, hex snapshot, raw chain spec or RPC url of a node to upgrade. Snapshots
+    /// must end in `.snap`.
+    #[clap(long)]
+    pub state_path: String,
+
+    /// The new runtime wasm.
+    #[clap(long)]
+    pub new_runtime: PathBuf,
+
+    /// Write the upgraded state into this raw chain spec, replacing all of its keys. If not
+    /// set, a hex snapshot is written to `--output-path`.
+    #[clap(long)]
+    pub chain_spec_path: Option<String>,
+
+    /// Output path. Defaults to the chain spec path if `--chain-spec-path` is set. A path ending
+    /// in `.snap` is written as a snapshot of the produced block, which keeps the child tries.
+    #[clap(long)]
+    pub output_path: Option<String>,
+
+    /// Maximum number of blocks to produce while waiting for multi-block migrations to finish.
+    #[clap(long, default_value_t = 100)]
+    pub max_blocks: u32,
+
+    #[command(flatten)]
+    pub block_config: BlockConfig,
+
+    #[command(flatten)]
+    pub rpc: RpcConfig,
+}
+
+/// The `Cursor` storage item of `pallet-migrations`, which exists while multi-block migrations
+/// are ongoing. The pallet can have any name in the runtime, so it is found by the type of the
+/// item.
+fn migration_cursor(runtime: &RuntimeInfo) -> Option<StorageItem<'_>> {
+    runtime.storage_items().find(|item| {
+        item.entry.name == "Cursor"
+            && runtime
+                .types
+                .resolve(item.value_type())
+                .is_some_and(|ty| ty.path.segments == ["pallet_migrations", "MigrationCursor"])
+    })
+}
+
+pub async fn upgrade<Block, HostFns>(
+    shared: SharedParams,
+    command: UpgradeCommand,
+) -> sc_cli::Result<()>
+where
+    Block: BlockT + serde::de::DeserializeOwned,
+    Block::Hash: serde::de::DeserializeOwned,
+    Block::Header: serde::de::DeserializeOwned,
+    <Block::Hash as FromStr>::Err: Debug,
+    NumberFor<Block>: FromStr,
+    <NumberFor<Block> as FromStr>::Err: Debug,
+    HostFns: HostFunctions,
+{
+    let output_path = match (&command.output_path, &command.chain_spec_path) {
+        (Some(output_path), _) => output_path.clone(),
+        (None, Some(chain_spec_path)) => chain_spec_path.clone(),
+        (None, None) => return Err("Pass --output-path or --chain-spec-path".into()),
+    };
+
+    let input = open_source::<Block>(&command.state_path, &command.rpc).await?;
+    if let Some(old_code) = input.code()? {
+        let old_version = fetch_runtime_version::<HostFns>(&shared, &old_code)?;
+        log::info!(
+            "Current runtime is {} version {}",
+            old_version.spec_name,
+            old_version.spec_version
+        );
+    }
+    let new_code = fs::read(&command.new_runtime).inspect_err(|e| {
+        log::error!("Failed to read new runtime: {}", e);
+    })?;
+    let new_version = fetch_runtime_version::<HostFns>(&shared, &new_code)?;
+    let runtime = load_runtime_info::<HostFns>(&shared, &new_code)?;
+    log::info!(
+        "Upgrading to {} version {}",
+        new_version.spec_name,
+        new_version.spec_version
+    );
+
+    let block_hash = input
+        .block_hash()
+        .map(|hash| Block::Hash::decode(&mut &hash[..]))
+        .transpose()?;
+    let mut ext = TestExternalities::new_with_state_version(
+        input.into_storage()?,
+        new_version.state_version(),
+    );
+    ext.insert(CODE_KEY.to_vec(), new_code);
+    ext.commit_all()?;
+
+    // The first block runs `on_runtime_upgrade`, the next ones step the multi-block migrations
+    let cursor_key = migration_cursor(&runtime).map(|item| {
+        log::info!("Multi-block migrations run in pallet {}", item.pallet);
+        item.prefix()
+    });
+    let mut producer =
+        BlockProducer::<Block, HostFns>::new(&shared, ext, block_hash, command.block_config)?;
+    let mut blocks = 0;
+    let header = loop {
+        if blocks == command.max_blocks {
+            return Err(format!(
+                "Multi-block migrations did not finish after {} blocks",
+                blocks
+            )
+            .into());
+        }
+        let header = producer.produce_block().inspect_err(|e| {
+            log::error!("Failed to produce block with the new runtime: {}", e);
+        })?;
+        blocks += 1;
+
+        let migrating = cursor_key.as_ref().is_some_and(|cursor_key| {
+            producer
+                .ext
+                .execute_with(|| sp_io::storage::exists(cursor_key))
+        });
+        if !migrating {
+            break header;
+        }
+        log::info!("Multi-block migrations ongoing after {} blocks", blocks);
+    };
+    log::info!("Migrations finished after {} blocks", blocks);
+    log::warn!("Block number and timestamp have advanced, use rebase-time to reset them");
+
+    let key_values = externalities_key_values(&mut producer.ext);
+    write_state::<Block>(
+        key_values,
+        header,
+        producer.ext.state_version,
+        command.chain_spec_path.as_deref(),
+        &output_path,
+    )?;
+
+    Ok(())
+}